@@ -0,0 +1,130 @@
+//! Aggregation of the responses to the requests a client request was split into, e.g. one for
+//! each preprocessed file a source location maps to, so that the client request is answered
+//! exactly once.
+
+use std::any::Any;
+use std::time::{Duration, Instant};
+
+use lsp_server::{ErrorCode, RequestId, Response, ResponseError};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Once some results are in, the missing responses are waited for this long before the client
+/// request is answered without them.
+pub const AGGREGATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Merge = Box<dyn FnOnce(Vec<Box<dyn Any>>) -> Value>;
+
+/// Pending responses to the requests a client request was split into.
+///
+/// Errors are ignored if any of the requests succeeded, the client request is answered with the
+/// merged successful results then. Otherwise it is answered with one error combining all errors.
+pub struct Aggregation {
+    /// Number of responses still missing.
+    pending: usize,
+    /// Mapped results of the successful responses.
+    results: Vec<Box<dyn Any>>,
+    errors: Vec<ResponseError>,
+    /// Merges the results, set by the first successful response as only it knows their type.
+    merge: Option<Merge>,
+    started: Instant,
+}
+
+impl Aggregation {
+    pub fn new(pending: usize) -> Self {
+        Self {
+            pending,
+            results: Vec::new(),
+            errors: Vec::new(),
+            merge: None,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn add_result<T: Serialize + 'static>(&mut self, result: T, merge: fn(Vec<T>) -> T) {
+        self.pending = self.pending.saturating_sub(1);
+        self.results.push(Box::new(result));
+        self.merge.get_or_insert_with(|| {
+            Box::new(move |results| {
+                let results =
+                    results.into_iter().map(|result| *result.downcast::<T>().unwrap()).collect();
+                serde_json::to_value(merge(results)).unwrap()
+            })
+        });
+    }
+
+    pub fn add_error(&mut self, error: ResponseError) {
+        self.pending = self.pending.saturating_sub(1);
+        self.errors.push(error);
+    }
+
+    /// Returns whether all responses are in.
+    pub fn is_complete(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// Returns whether the client request should be answered with the results that are in, as
+    /// the missing responses take too long.
+    pub fn is_overdue(&self) -> bool {
+        !self.results.is_empty() && self.started.elapsed() > AGGREGATION_TIMEOUT
+    }
+
+    /// Builds the answer to the client request with the given id.
+    pub fn into_response(self, id: RequestId) -> Response {
+        if let Some(merge) = self.merge {
+            for error in &self.errors {
+                warn!("Ignore error in response to split request {}: {}", id, error.message);
+            }
+            return Response { id, result: Some(merge(self.results)), error: None };
+        }
+
+        let Some(first) = self.errors.first() else {
+            return Response { id, result: Some(Value::Null), error: None };
+        };
+        let code = match self.errors.iter().all(|error| error.code == first.code) {
+            true => first.code,
+            false => ErrorCode::RequestFailed as i32,
+        };
+        let mut messages: Vec<&str> = Vec::new();
+        for error in &self.errors {
+            if !messages.contains(&error.message.as_str()) {
+                messages.push(&error.message);
+            }
+        }
+        Response::new_err(id, code, messages.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(code: ErrorCode, message: &str) -> ResponseError {
+        ResponseError { code: code as i32, message: message.to_owned(), data: None }
+    }
+
+    #[test]
+    fn aggregate_responses() {
+        let concat = |results: Vec<Vec<u32>>| results.concat();
+
+        let mut aggregation = Aggregation::new(3);
+        aggregation.add_result(vec![1, 2], concat);
+        aggregation.add_error(error(ErrorCode::InternalError, "crashed"));
+        assert!(!aggregation.is_complete());
+        assert!(!aggregation.is_overdue());
+        aggregation.add_result(vec![3], concat);
+        assert!(aggregation.is_complete());
+        let res = aggregation.into_response(RequestId::from(1));
+        assert_eq!(res.result, Some(serde_json::json!([1, 2, 3])));
+        assert!(res.error.is_none());
+
+        let mut aggregation = Aggregation::new(3);
+        aggregation.add_error(error(ErrorCode::InternalError, "crashed"));
+        aggregation.add_error(error(ErrorCode::RequestFailed, "not running"));
+        aggregation.add_error(error(ErrorCode::InternalError, "crashed"));
+        let res = aggregation.into_response(RequestId::from(1));
+        let error = res.error.unwrap();
+        assert_eq!(error.code, ErrorCode::RequestFailed as i32);
+        assert_eq!(error.message, "crashed\nnot running");
+    }
+}
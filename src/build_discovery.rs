@@ -0,0 +1,205 @@
+//! Discovers the build directories of the opened Fiasco source tree, for when no build directory
+//! is given on the command line.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::{eyre, Result};
+use lsp_server::{Connection, Message, RequestId};
+use lsp_types::notification::ShowMessage;
+use lsp_types::request::ShowMessageRequest;
+use lsp_types::{
+    InitializeParams, MessageActionItem, MessageType, ShowMessageParams, ShowMessageRequestParams,
+};
+
+use crate::util::{build_notif, build_req};
+
+/// How many directory levels below the source tree and search directories are searched.
+const MAX_DEPTH: usize = 2;
+
+/// Files that every configured build directory contains.
+const BUILD_DIR_FILES: [&str; 2] = ["globalconfig.out", ".Modules.deps"];
+
+/// How long to wait for the user to select one of multiple build directories.
+const SELECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Candidate {
+    pub build_dir: PathBuf,
+    /// Time of the last build, i.e. of the last change to `.Modules.deps`.
+    pub built: SystemTime,
+}
+
+/// Returns whether the directory is a build directory whose `source` symlink points into the
+/// given source tree (or the other way round).
+fn is_build_dir_of(dir: &Path, fiasco_dir: &Path) -> bool {
+    let source = dir.join("source");
+    if !BUILD_DIR_FILES.iter().all(|name| dir.join(name).is_file())
+        || !fs::symlink_metadata(&source).is_ok_and(|metadata| metadata.is_symlink())
+    {
+        return false;
+    }
+    source
+        .canonicalize()
+        .is_ok_and(|source| source.starts_with(fiasco_dir) || fiasco_dir.starts_with(source))
+}
+
+fn search(dir: &Path, depth: usize, fiasco_dir: &Path, found: &mut Vec<PathBuf>) {
+    if is_build_dir_of(dir, fiasco_dir) {
+        found.push(dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()));
+        return;
+    }
+    if depth == MAX_DEPTH {
+        return;
+    }
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        // Symlinks are not followed, e.g. the `source` symlink leads back into the source tree.
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if is_dir && !entry.file_name().to_string_lossy().starts_with('.') {
+            search(&entry.path(), depth + 1, fiasco_dir, found);
+        }
+    }
+}
+
+/// Searches the source tree and the search directories for build directories of the source
+/// tree. Returns the build directories, the most recently built first.
+pub fn discover(fiasco_dir: &Path, search_dirs: &[PathBuf]) -> Vec<Candidate> {
+    let fiasco_dir = fiasco_dir.canonicalize().unwrap_or_else(|_| fiasco_dir.to_path_buf());
+    let mut found = Vec::new();
+    for dir in std::iter::once(&fiasco_dir).chain(search_dirs) {
+        search(dir, 0, &fiasco_dir, &mut found);
+    }
+    found.sort();
+    found.dedup();
+
+    let mut candidates: Vec<Candidate> = found
+        .into_iter()
+        .map(|build_dir| {
+            let built = fs::metadata(build_dir.join(".Modules.deps"))
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            Candidate { build_dir, built }
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.built));
+    candidates
+}
+
+/// Returns the root directory of the workspace opened by the client.
+fn workspace_root(params: &InitializeParams) -> Option<PathBuf> {
+    let folder = params.workspace_folders.as_ref().and_then(|folders| folders.first());
+    let uri = folder.map(|folder| &folder.uri).or(params.root_uri.as_ref());
+    #[allow(deprecated)]
+    let root_path = params.root_path.as_ref().map(PathBuf::from);
+    uri.and_then(|uri| uri.to_file_path().ok()).or(root_path)
+}
+
+/// Asks the user via `window/showMessageRequest` which of the build directories to use. Returns
+/// `None` if the user did not select one in time.
+fn ask(connection: &Connection, candidates: &[Candidate]) -> Option<PathBuf> {
+    let actions = candidates
+        .iter()
+        .map(|candidate| MessageActionItem {
+            title: candidate.build_dir.display().to_string(),
+            properties: HashMap::new(),
+        })
+        .collect();
+    let params = ShowMessageRequestParams {
+        typ: MessageType::INFO,
+        message: "Multiple Fiasco build directories found, which one should be used?".to_owned(),
+        actions: Some(actions),
+    };
+    let id = RequestId::from("selectBuildDir".to_owned());
+    connection.sender.send(build_req::<ShowMessageRequest>(id.clone(), params).into()).ok()?;
+    loop {
+        match connection.receiver.recv_timeout(SELECT_TIMEOUT) {
+            Ok(Message::Response(res)) if res.id == id => {
+                let item: Option<MessageActionItem> = serde_json::from_value(res.result?).ok()?;
+                return item.map(|item| PathBuf::from(item.title));
+            }
+            Ok(msg) => warn!("Ignore message while waiting for build directory: {:?}", msg),
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Selects the build directory for the workspace of the client, while the client waits for the
+/// response to `initialize`. If there are multiple build directories, the user is asked, falling
+/// back to the most recently built one.
+pub fn select_build_dir(
+    connection: &Connection,
+    params: &InitializeParams,
+    search_dirs: &[PathBuf],
+) -> Result<PathBuf> {
+    let root = workspace_root(params);
+    let candidates = match &root {
+        Some(root) => discover(root, search_dirs),
+        None => Vec::new(),
+    };
+    debug!("Build directory candidates: {:?}", candidates);
+
+    let build_dir = match candidates.len() {
+        0 => None,
+        1 => Some(candidates[0].build_dir.clone()),
+        _ => ask(connection, &candidates).or_else(|| Some(candidates[0].build_dir.clone())),
+    };
+    build_dir.ok_or_else(|| {
+        let message = match root {
+            Some(root) => format!(
+                "No Fiasco build directory found for {}, use --build-dir or --search-dir.",
+                root.display()
+            ),
+            None => "No workspace opened, use --build-dir to select the Fiasco build directory."
+                .to_owned(),
+        };
+        let notif = build_notif::<ShowMessage>(ShowMessageParams {
+            typ: MessageType::ERROR,
+            message: message.clone(),
+        });
+        let _ = connection.sender.send(notif.into());
+        eyre!(message)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn discover_build_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        let fiasco_dir = dir.join("fiasco");
+        let other_dir = dir.join("other");
+        let build_dir = |path: &Path, source: &Path, built: u64| {
+            fs::create_dir_all(path).unwrap();
+            for name in BUILD_DIR_FILES {
+                fs::write(path.join(name), "").unwrap();
+            }
+            symlink(source, path.join("source")).unwrap();
+            let built = SystemTime::UNIX_EPOCH + Duration::from_secs(built);
+            fs::File::options()
+                .write(true)
+                .open(path.join(".Modules.deps"))
+                .unwrap()
+                .set_modified(built)
+                .unwrap();
+        };
+        fs::create_dir_all(fiasco_dir.join("src")).unwrap();
+        fs::create_dir_all(&other_dir).unwrap();
+        build_dir(&fiasco_dir.join("build/arm64"), &fiasco_dir.join("src"), 100);
+        build_dir(&dir.join("builds/amd64"), &fiasco_dir.join("src"), 200);
+        build_dir(&dir.join("builds/other"), &other_dir, 300);
+        // Too deep below the source tree.
+        build_dir(&fiasco_dir.join("a/b/c"), &fiasco_dir.join("src"), 400);
+
+        let candidates = discover(&fiasco_dir, &[dir.join("builds")]);
+        let build_dirs: Vec<&Path> =
+            candidates.iter().map(|candidate| candidate.build_dir.as_path()).collect();
+        assert_eq!(build_dirs, [dir.join("builds/amd64"), fiasco_dir.join("build/arm64")]);
+    }
+}
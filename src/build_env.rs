@@ -1,18 +1,197 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
 
+use color_eyre::eyre::{eyre, Result, WrapErr};
 use tempfile::{tempdir, TempDir};
 
+use crate::source_mapping::{self, load_modules};
+
 #[derive(Debug)]
 pub struct BuildEnv {
     pub build_dir: PathBuf,
     pub source_dir: PathBuf,
     pub config: PathBuf,
+    /// Invocations of preprocess by module, shared with the preprocess worker.
+    preprocess_commands: Arc<Mutex<HashMap<String, PreprocessCommand>>>,
     _temp_dir: Option<TempDir>,
 }
 
+/// The part of a build environment needed to run preprocess, which can be moved to a worker
+/// thread, see `preprocess_worker`.
+#[derive(Clone, Debug)]
+pub struct Preprocessor {
+    pub build_dir: PathBuf,
+    commands: Arc<Mutex<HashMap<String, PreprocessCommand>>>,
+}
+
+impl Preprocessor {
+    /// Re-runs preprocess for the given modules, by building their `auto/stamp-*.ready`
+    /// targets.
+    pub fn preprocess(&self, modules: &[String]) -> Result<()> {
+        if modules.is_empty() {
+            return Ok(());
+        }
+        try_cmd(
+            new_make_cmd()
+                .args(modules.iter().map(|module| format!("auto/stamp-{module}.ready")))
+                .current_dir(&self.build_dir),
+            "Unable to preprocess.",
+        )?;
+        Ok(())
+    }
+
+    /// Runs preprocess for a module, in a temporary directory, with the given source files replaced
+    /// by their unsaved content. Returns the generated files, without touching the build
+    /// directory.
+    pub fn preprocess_overlay(
+        &self,
+        module: &str,
+        sources: &HashMap<PathBuf, String>,
+    ) -> Result<Vec<(PathBuf, String)>> {
+        // The lock is not held while make runs, the main loop might wait for it.
+        let cached = self.commands.lock().unwrap().get(module).cloned();
+        let command = match cached {
+            Some(command) => command,
+            None => {
+                // Ask make how it would run preprocess for the module.
+                let output = try_cmd(
+                    Command::new("make")
+                        .args(["--dry-run", "--always-make", &format!("auto/stamp-{module}.ready")])
+                        .current_dir(&self.build_dir),
+                    "Unable to query preprocess command.",
+                )?;
+                let command = String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .find_map(|line| PreprocessCommand::parse(&self.build_dir, line))
+                    .ok_or_else(|| eyre!("No preprocess command found for module {module}."))?;
+                debug!("Preprocess command for module {}: {:?}", module, command);
+                self.commands.lock().unwrap().insert(module.to_owned(), command.clone());
+                command
+            }
+        };
+        command.run_with_overlay(sources)
+    }
+}
+
+/// Invocation of the preprocess tool for a module, as extracted from the build system.
+#[derive(Clone, Debug)]
+struct PreprocessCommand {
+    cwd: PathBuf,
+    envs: Vec<(String, String)>,
+    program: String,
+    args: Vec<String>,
+}
+
+impl PreprocessCommand {
+    /// Parses a command printed by `make --dry-run`, which might be prefixed by `cd <dir> &&`
+    /// and environment variable assignments.
+    fn parse(build_dir: &Path, line: &str) -> Option<PreprocessCommand> {
+        let mut words = shell_words::split(line).ok()?.into_iter().peekable();
+        let mut cwd = build_dir.to_path_buf();
+        let mut envs = Vec::new();
+        loop {
+            match words.peek().map(String::as_str) {
+                Some("cd") => {
+                    words.next();
+                    cwd = cwd.join(words.next()?);
+                    if words.next()? != "&&" {
+                        return None;
+                    }
+                }
+                Some(word) if !word.starts_with('-') && word.contains('=') => {
+                    let (key, value) = word.split_once('=').unwrap();
+                    envs.push((key.to_owned(), value.to_owned()));
+                    words.next();
+                }
+                _ => break,
+            }
+        }
+
+        let program = words.next()?;
+        let args: Vec<String> =
+            words.take_while(|word| !matches!(word.as_str(), "&&" | "||" | ";" | "|")).collect();
+        let is_preprocess =
+            |word: &String| Path::new(word).file_name().is_some_and(|name| name == "preprocess");
+        if !is_preprocess(&program) && !args.first().is_some_and(is_preprocess) {
+            return None;
+        }
+
+        Some(PreprocessCommand { cwd, envs, program, args })
+    }
+
+    /// Runs the command with the given source files replaced by their (unsaved) content. Returns
+    /// the path of each generated file in the build directory together with its content.
+    fn run_with_overlay(
+        &self,
+        sources: &HashMap<PathBuf, String>,
+    ) -> Result<Vec<(PathBuf, String)>> {
+        let temp_dir = tempdir().wrap_err("Unable to create temporary preprocess dir.")?;
+        let out_dir = temp_dir.path().join("out");
+        fs::create_dir(&out_dir)?;
+
+        let mut args = Vec::new();
+        let mut real_out_dir = None;
+        // Temporary source files with the path preprocess would have seen otherwise.
+        let mut replacements = Vec::new();
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            // Output base names for the generated implementation and header files.
+            if arg == "-c" || arg == "-h" {
+                let base = self.cwd.join(iter.next().ok_or_else(|| eyre!("Missing {arg} value."))?);
+                real_out_dir = base.parent().map(Path::to_path_buf);
+                args.push(arg.clone());
+                args.push(out_dir.join(base.file_name().unwrap()).to_str().unwrap().to_owned());
+                continue;
+            }
+
+            let path = self.cwd.join(arg).canonicalize().ok();
+            let source = sources
+                .iter()
+                .find(|(source, _)| path.is_some() && source.canonicalize().ok() == path);
+            match source {
+                Some((source, text)) => {
+                    // Keep the file name, preprocess derives some information from it.
+                    let temp_source = temp_dir
+                        .path()
+                        .join(replacements.len().to_string())
+                        .join(source.file_name().unwrap());
+                    fs::create_dir(temp_source.parent().unwrap())?;
+                    fs::write(&temp_source, text)?;
+                    args.push(temp_source.to_str().unwrap().to_owned());
+                    replacements.push((temp_source, arg));
+                }
+                None => args.push(arg.clone()),
+            }
+        }
+        let real_out_dir =
+            real_out_dir.ok_or_else(|| eyre!("Unable to determine preprocess output."))?;
+
+        try_cmd(
+            Command::new(&self.program)
+                .args(&args)
+                .envs(self.envs.iter().cloned())
+                .current_dir(&self.cwd),
+            "Unable to preprocess unsaved sources.",
+        )?;
+
+        let mut generated = Vec::new();
+        for entry in fs::read_dir(&out_dir)? {
+            let path = entry?.path();
+            let mut text = fs::read_to_string(&path)?;
+            // Restore the source paths referenced in the #line directives.
+            for (temp_source, source) in &replacements {
+                text = text.replace(temp_source.to_str().unwrap(), source);
+            }
+            generated.push((real_out_dir.join(path.file_name().unwrap()), text));
+        }
+        Ok(generated)
+    }
+}
+
 fn check_cmd(cmd: &mut Command, message: &str) -> Output {
     let output = cmd.output().expect(message);
     if !output.status.success() {
@@ -21,6 +200,16 @@ fn check_cmd(cmd: &mut Command, message: &str) -> Output {
     output
 }
 
+/// Like `check_cmd`, but reports a failure to the caller instead of panicking, which is
+/// what we want for builds triggered while the proxy is running.
+fn try_cmd(cmd: &mut Command, message: &str) -> Result<Output> {
+    let output = cmd.output().wrap_err_with(|| message.to_owned())?;
+    if !output.status.success() {
+        return Err(eyre!("{message}: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(output)
+}
+
 fn new_make_cmd() -> Command {
     let mut cmd = Command::new("make");
     cmd.arg(format!("-j{}", std::thread::available_parallelism().unwrap()));
@@ -33,6 +222,7 @@ impl BuildEnv {
             build_dir: build_dir.to_path_buf(),
             source_dir: build_dir.join("source"),
             config: build_dir.join("config"),
+            preprocess_commands: Arc::default(),
             _temp_dir: None,
         }
     }
@@ -66,17 +256,97 @@ impl BuildEnv {
             build_dir,
             source_dir: source_dir.to_path_buf(),
             config: config.to_path_buf(),
+            preprocess_commands: Arc::default(),
             _temp_dir: Some(temp_dir),
         }
     }
 
-    pub fn gen_compile_commands(&self) {
+    pub fn gen_compile_commands(&self) -> Result<()> {
         // Make .Module.deps and compile_commands.json
-        check_cmd(
+        try_cmd(
             new_make_cmd()
                 .args([".Modules.deps", "compile_commands.json"])
                 .current_dir(&self.build_dir),
             "Unable to build.",
+        )?;
+        Ok(())
+    }
+
+    /// Returns the modules whose preprocess inputs include the given source file.
+    pub fn modules_for_source(&self, source: &Path) -> Vec<String> {
+        let canonical_source = source.canonicalize().ok();
+        load_modules(self.build_dir.to_str().unwrap())
+            .into_iter()
+            .filter(|(_, deps)| {
+                deps.iter().map(Path::new).any(|dep| {
+                    dep == source
+                        || (dep.is_relative() && source.ends_with(dep))
+                        || (canonical_source.is_some()
+                            && self.build_dir.join(dep).canonicalize().ok() == canonical_source)
+                })
+            })
+            .map(|(module, _)| module)
+            .collect()
+    }
+
+    /// Returns a handle to run preprocess in the build directory from another thread.
+    pub fn preprocessor(&self) -> Preprocessor {
+        Preprocessor {
+            build_dir: self.build_dir.clone(),
+            commands: self.preprocess_commands.clone(),
+        }
+    }
+
+    /// Drops the cached preprocess commands, e.g. after the modules of the build directory changed.
+    pub fn forget_preprocess_commands(&mut self) {
+        self.preprocess_commands.lock().unwrap().clear();
+    }
+
+    /// Returns the preprocessed files generated for a module that exist in the build directory.
+    pub fn module_outputs(&self, module: &str) -> Vec<PathBuf> {
+        source_mapping::module_outputs(&self.build_dir, module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dry_run() {
+        let build_dir = Path::new("/build");
+        let line = "cd auto && PATH=\"/opt/bin:$PATH\" LC_ALL=C \
+            perl /fiasco/tool/preprocess/src/preprocess -e '' -c kernel -h kernel_h \
+            ../src/kern/kernel_thread.cpp && touch kernel.stamp";
+        let cmd = PreprocessCommand::parse(build_dir, line).unwrap();
+        assert_eq!(cmd.cwd, Path::new("/build/auto"));
+        assert_eq!(
+            cmd.envs,
+            [
+                ("PATH".to_owned(), "/opt/bin:$PATH".to_owned()),
+                ("LC_ALL".to_owned(), "C".to_owned())
+            ]
         );
+        assert_eq!(cmd.program, "perl");
+        assert_eq!(
+            cmd.args,
+            [
+                "/fiasco/tool/preprocess/src/preprocess",
+                "-e",
+                "",
+                "-c",
+                "kernel",
+                "-h",
+                "kernel_h",
+                "../src/kern/kernel_thread.cpp"
+            ]
+        );
+
+        let cmd = PreprocessCommand::parse(build_dir, "preprocess -c foo src/foo.cpp").unwrap();
+        assert_eq!(cmd.cwd, build_dir);
+        assert_eq!(cmd.program, "preprocess");
+
+        assert!(PreprocessCommand::parse(build_dir, "echo PREPROCESS kernel").is_none());
+        assert!(PreprocessCommand::parse(build_dir, "cd auto; preprocess foo.cpp").is_none());
     }
 }
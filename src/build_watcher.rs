@@ -0,0 +1,97 @@
+//! Polls the build directory for files regenerated by the build system, e.g. by a `make` run in a
+//! terminal.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crossbeam_channel::RecvTimeoutError;
+
+use crate::thread_worker::Worker;
+
+/// Interval in which the build directory is scanned for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Files of the build directory that describe its modules.
+pub const MODULE_FILES: [&str; 2] = [".Modules.deps", "compile_commands.json"];
+
+#[derive(Debug)]
+pub enum BuildEvent {
+    /// Preprocessed files were created, modified or removed.
+    Preprocessed(Vec<PathBuf>),
+    /// `.Modules.deps` or `compile_commands.json` changed.
+    Modules,
+}
+
+type Snapshot = HashMap<PathBuf, (SystemTime, u64)>;
+
+fn is_preprocessed_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "cpp" || ext == "h" || ext == "cc")
+}
+
+fn snapshot(build_dir: &Path) -> Snapshot {
+    let auto_files = fs::read_dir(build_dir.join("auto"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_preprocessed_file(path));
+    let module_files = MODULE_FILES.iter().map(|name| build_dir.join(name));
+    auto_files
+        .chain(module_files)
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            Some((path, (metadata.modified().ok()?, metadata.len())))
+        })
+        .collect()
+}
+
+fn changed_files(old: &Snapshot, new: &Snapshot) -> BTreeSet<PathBuf> {
+    let modified = new.iter().filter(|(path, stat)| old.get(*path) != Some(stat));
+    let removed = old.iter().filter(|(path, _)| !new.contains_key(*path));
+    modified.chain(removed).map(|(path, _)| path.clone()).collect()
+}
+
+/// Spawns a worker that reports changes in the build directory. Changes are only reported once
+/// the build directory has settled, so that a running build is not reported file by file.
+pub fn spawn(build_dir: PathBuf) -> Worker<(), BuildEvent> {
+    Worker::spawn("Build directory watcher", 1, move |receiver, sender| {
+        let mut last = snapshot(&build_dir);
+        let mut pending = BTreeSet::new();
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+
+            let current = snapshot(&build_dir);
+            let changed = changed_files(&last, &current);
+            last = current;
+            if !changed.is_empty() {
+                pending.extend(changed);
+                continue;
+            }
+            if pending.is_empty() {
+                continue;
+            }
+
+            let (module_files, preprocessed): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut pending).into_iter().partition(|path| {
+                    MODULE_FILES.iter().any(|name| path.file_name().is_some_and(|n| n == *name))
+                });
+            debug!("Build directory changed: {:?} {:?}", module_files, preprocessed);
+            let mut events = Vec::new();
+            if !preprocessed.is_empty() {
+                events.push(BuildEvent::Preprocessed(preprocessed));
+            }
+            if !module_files.is_empty() {
+                events.push(BuildEvent::Modules);
+            }
+            for event in events {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
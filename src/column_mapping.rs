@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+/// Maps a run of columns of a source line to the same run of characters in the mapped line.
+/// Columns are counted in UTF-16 code units, like LSP positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnSegment {
+    pub src_column: u32,
+    pub dst_column: u32,
+    pub length: u32,
+}
+
+impl ColumnSegment {
+    fn src_end(&self) -> u32 {
+        self.src_column + self.length
+    }
+
+    pub fn inverse(&self) -> ColumnSegment {
+        ColumnSegment { src_column: self.dst_column, dst_column: self.src_column, ..*self }
+    }
+}
+
+/// Splits a line into identifiers/numbers, whitespace runs and single other characters. Returns
+/// the tokens with their start column.
+fn tokenize(line: &str) -> Vec<(u32, &str)> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+
+    let mut tokens: Vec<(u32, &str)> = Vec::new();
+    let mut column = 0;
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        let next_same_class =
+            chars.peek().is_some_and(|&(_, next)| class(next) == class(c) && class(c) != 2);
+        if !next_same_class {
+            let end = offset + c.len_utf8();
+            tokens.push((column, &line[start..end]));
+            column += line[start..end].encode_utf16().count() as u32;
+            start = end;
+        }
+    }
+    tokens
+}
+
+/// Computes the column segments of `src` that are kept unchanged in `dst`, based on the longest
+/// common subsequence of their tokens. Columns that are not covered by a segment were removed
+/// (e.g. `PUBLIC`) or inserted (e.g. `Class::`) by preprocess.
+pub fn diff_columns(src: &str, dst: &str) -> Vec<ColumnSegment> {
+    let src_tokens = tokenize(src);
+    let dst_tokens = tokenize(dst);
+    let (n, m) = (src_tokens.len(), dst_tokens.len());
+
+    // lcs[i][j]: Length of the longest common subsequence of src_tokens[i..] and dst_tokens[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if src_tokens[i].1 == dst_tokens[j].1 {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut segments: Vec<ColumnSegment> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if src_tokens[i].1 == dst_tokens[j].1 {
+            let (src_column, token) = src_tokens[i];
+            let dst_column = dst_tokens[j].0;
+            let length = token.encode_utf16().count() as u32;
+            match segments.last_mut() {
+                // Merge adjacent matches.
+                Some(last)
+                    if last.src_end() == src_column
+                        && last.dst_column + last.length == dst_column =>
+                {
+                    last.length += length
+                }
+                _ => segments.push(ColumnSegment { src_column, dst_column, length }),
+            }
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    segments
+}
+
+/// Maps a column via the given segments (sorted by column). Columns in front of a segment, that
+/// have no counterpart, are mapped to the start of that segment.
+pub fn map_column(segments: &[ColumnSegment], column: u32) -> u32 {
+    for segment in segments {
+        if column < segment.src_column {
+            return segment.dst_column;
+        }
+        if column < segment.src_end() {
+            return segment.dst_column + (column - segment.src_column);
+        }
+    }
+
+    match segments.last() {
+        Some(last) => last.dst_column + last.length + (column - last.src_end()),
+        None => column,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let src = "PUBLIC inline NEEDS[\"foo.h\"] void bar(int x)";
+        let dst = "inline void Foo::bar(int x)";
+        let segments = diff_columns(src, dst);
+
+        // "inline"
+        assert_eq!(map_column(&segments, 7), 0);
+        // "bar"
+        assert_eq!(map_column(&segments, 34), 17);
+        // "x"
+        assert_eq!(map_column(&segments, 42), 25);
+        // "PUBLIC" has no counterpart.
+        assert_eq!(map_column(&segments, 2), 0);
+
+        let inverse: Vec<_> = segments.iter().map(ColumnSegment::inverse).collect();
+        assert_eq!(map_column(&inverse, 17), 34);
+        // "Foo::" has no counterpart, map to "bar".
+        assert_eq!(map_column(&inverse, 13), 34);
+    }
+}
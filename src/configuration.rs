@@ -0,0 +1,297 @@
+//! Fiasco configurations served side by side, e.g. the same source tree built for different
+//! architectures. Each configuration has its own build directory, language server and line
+//! mapping.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result};
+use lsp_server::{Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{Initialized, Notification as _};
+use lsp_types::request::{Initialize, Request as _};
+
+use crate::build_env::BuildEnv;
+use crate::build_watcher::{self, BuildEvent};
+use crate::language_server_transport::{self, LanguageServerTransport};
+use crate::settings::Settings;
+use crate::source_mapping::{FiascoSourceMapping, MapDirection, PreprocessSection, SourceLocation};
+use crate::thread_worker::Worker;
+
+/// Index of the configuration that handles requests not related to a preprocessed file, e.g.
+/// `workspace/symbol`.
+pub const PRIMARY: usize = 0;
+
+/// How long to wait for the language server to answer `initialize`.
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Configuration {
+    /// Name shown to the user, the name of the build directory.
+    pub name: String,
+    pub build_env: BuildEnv,
+    pub server: LanguageServerTransport,
+    pub build_watcher: Worker<(), BuildEvent>,
+    /// Whether the language server is running, i.e. it was not given up after it kept crashing.
+    pub running: bool,
+    /// When the language server was restarted after it crashed or hang.
+    pub restarts: Vec<Instant>,
+    /// When the language server was started or last sent a message, see `handle_watchdog`.
+    pub last_message: Instant,
+}
+
+impl Configuration {
+    pub fn new(build_env: BuildEnv, server: LanguageServerTransport) -> Configuration {
+        let name = build_env.build_dir.file_name().map_or_else(
+            || build_env.build_dir.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        Configuration {
+            name,
+            build_watcher: build_watcher::spawn(build_env.build_dir.clone()),
+            build_env,
+            server,
+            running: true,
+            restarts: Vec::new(),
+            last_message: Instant::now(),
+        }
+    }
+
+    /// Starts the language server for a build directory and initializes it with the
+    /// `initialize` params of the client. Returns the configuration and the result of
+    /// `initialize`.
+    pub fn start(
+        build_env: BuildEnv,
+        settings: &Settings,
+        initialize_params: &serde_json::Value,
+    ) -> Result<(Configuration, serde_json::Value)> {
+        let (server, result) = start_server(&build_env, settings, initialize_params)?;
+        Ok((Configuration::new(build_env, server), result))
+    }
+}
+
+/// Starts and initializes the language server for a build directory, see `Configuration::start`.
+pub fn start_server(
+    build_env: &BuildEnv,
+    settings: &Settings,
+    initialize_params: &serde_json::Value,
+) -> Result<(LanguageServerTransport, serde_json::Value)> {
+    let args = settings.clangd_args(&build_env.build_dir)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let envs: Vec<(&str, &str)> =
+        settings.clangd_env().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+    let server = language_server_transport::start(settings.clangd_path(), &args, &envs)?;
+    let request = Request::new(
+        RequestId::from("initialize".to_owned()),
+        Initialize::METHOD.to_owned(),
+        initialize_params,
+    );
+    server.to_lang_server.sender().send(Message::Request(request))?;
+    let result = match server.from_lang_server.receiver().recv_timeout(INITIALIZE_TIMEOUT)? {
+        Message::Response(Response { result: Some(result), .. }) => result,
+        msg => return Err(eyre!("Received invalid initialize response from server: {msg:?}")),
+    };
+    let initialized = Notification::new(Initialized::METHOD.to_owned(), serde_json::json!({}));
+    server.to_lang_server.sender().send(Message::Notification(initialized))?;
+    Ok((server, result))
+}
+
+/// The line mappings of all configurations. Maps a source file to the preprocessed files of all
+/// configurations, ordered by configuration, and a preprocessed file back via the mapping of its
+/// configuration.
+pub struct SourceMappings {
+    /// Build directory and line mapping of each configuration.
+    mappings: Vec<(PathBuf, FiascoSourceMapping)>,
+}
+
+impl SourceMappings {
+    pub fn new() -> SourceMappings {
+        SourceMappings { mappings: Vec::new() }
+    }
+
+    pub fn push(&mut self, build_dir: &Path, source_mapping: FiascoSourceMapping) {
+        self.mappings.push((build_dir.to_path_buf(), source_mapping));
+    }
+
+    pub fn get(&self, config: usize) -> &FiascoSourceMapping {
+        &self.mappings[config].1
+    }
+
+    pub fn get_mut(&mut self, config: usize) -> &mut FiascoSourceMapping {
+        &mut self.mappings[config].1
+    }
+
+    /// Replaces the line mapping of a configuration, e.g. after switching its build directory.
+    pub fn replace(
+        &mut self,
+        config: usize,
+        build_dir: &Path,
+        source_mapping: FiascoSourceMapping,
+    ) {
+        self.mappings[config] = (build_dir.to_path_buf(), source_mapping);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &FiascoSourceMapping> {
+        self.mappings.iter().map(|(_, source_mapping)| source_mapping)
+    }
+
+    /// Returns the configuration the given preprocessed file belongs to.
+    pub fn config_of(&self, path: &Path) -> Option<usize> {
+        self.iter()
+            .position(|source_mapping| source_mapping.contains_file(path))
+            .or_else(|| self.mappings.iter().position(|(build_dir, _)| path.starts_with(build_dir)))
+    }
+
+    /// Returns the mapping of the first configuration that maps the given source file, for
+    /// requests that are only sent to one configuration, e.g. document symbols.
+    pub fn preferred(&self, path: &str) -> &FiascoSourceMapping {
+        self.iter()
+            .find(|source_mapping| {
+                !source_mapping.map_files(MapDirection::ToPreprocess, path).is_empty()
+            })
+            .unwrap_or(self.get(PRIMARY))
+    }
+
+    /// See `FiascoSourceMapping::map_all`, the locations are ordered by configuration.
+    pub fn map_all(
+        &self,
+        direction: MapDirection,
+        path: &str,
+        line: u32,
+        character: u32,
+    ) -> Vec<(SourceLocation, PreprocessSection)> {
+        self.iter()
+            .flat_map(|source_mapping| source_mapping.map_all(direction, path, line, character))
+            .collect()
+    }
+
+    /// Maps a position to the first location returned by `map_all`, or to itself if it is not
+    /// mapped at all.
+    pub fn map(
+        &self,
+        direction: MapDirection,
+        path: &str,
+        line: u32,
+        character: u32,
+    ) -> SourceLocation {
+        match self.map_all(direction, path, line, character).into_iter().next() {
+            None => {
+                debug!("No mapping found for Line {} ({})", line, path);
+                SourceLocation { path: PathBuf::from(path), line, character }
+            }
+            Some((location, _)) => location,
+        }
+    }
+
+    pub fn map_files(&self, direction: MapDirection, path: &str) -> Vec<PathBuf> {
+        self.iter()
+            .flat_map(|source_mapping| source_mapping.map_files(direction, path))
+            .cloned()
+            .collect()
+    }
+
+    pub fn map_files_with_range(
+        &self,
+        direction: MapDirection,
+        path: &str,
+        start: u32,
+        end: u32,
+    ) -> HashSet<&Path> {
+        self.iter()
+            .flat_map(|source_mapping| {
+                source_mapping.map_files_with_range(direction, path, start, end)
+            })
+            .collect()
+    }
+
+    pub fn file_length(&self, direction: MapDirection, path: &Path) -> Option<u32> {
+        self.iter().find_map(|source_mapping| source_mapping.file_length(direction, path))
+    }
+
+    pub fn contains_file(&self, path: &Path) -> bool {
+        self.iter().any(|source_mapping| source_mapping.contains_file(path))
+    }
+
+    pub fn module_of(&self, path: &Path) -> Option<&str> {
+        self.iter().find_map(|source_mapping| source_mapping.module_of(path))
+    }
+
+    /// See `FiascoSourceMapping::shift_lines`, applies to all configurations.
+    pub fn shift_lines(&mut self, path: &Path, start: u32, end: u32, new_end: u32) {
+        for (_, source_mapping) in &mut self.mappings {
+            source_mapping.shift_lines(path, start, end, new_end);
+        }
+    }
+
+    /// Re-reads the mappings of a preprocessed file into the mapping of its configuration.
+    pub fn reload_file(&mut self, path: &Path) {
+        match self.config_of(path) {
+            Some(config) => self.get_mut(config).reload_file(path),
+            None => warn!("No configuration found for {}.", path.display()),
+        }
+    }
+
+    pub fn reload_file_from_text(
+        &mut self,
+        path: &Path,
+        text: &str,
+        sources: &HashMap<PathBuf, String>,
+    ) {
+        match self.config_of(path) {
+            Some(config) => self.get_mut(config).reload_file_from_text(path, text, sources),
+            None => warn!("No configuration found for {}.", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lsp_types::{Position, Range};
+
+    use super::*;
+    use crate::source_mapping::load_source_mapping;
+    use crate::source_mapping::MapDirection::{FromPreprocess, ToPreprocess};
+
+    #[test]
+    fn mappings() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        let mut source_mappings = SourceMappings::new();
+        for name in ["arm64", "amd64"] {
+            let build_dir = dir.path().join(name);
+            fs::create_dir_all(build_dir.join("auto")).unwrap();
+            fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+            fs::write(
+                build_dir.join("auto/foo.cpp"),
+                format!("// {}\n#line 1 \"{}\"\nint a;\n", name, source.display()),
+            )
+            .unwrap();
+            let (source_mapping, _) = load_source_mapping(&build_dir);
+            source_mappings.push(&build_dir, source_mapping);
+        }
+
+        let arm64 = dir.path().join("arm64/auto/foo.cpp");
+        let amd64 = dir.path().join("amd64/auto/foo.cpp");
+        assert_eq!(
+            source_mappings.map_files(ToPreprocess, source.to_str().unwrap()),
+            [arm64.clone(), amd64.clone()]
+        );
+        assert_eq!(source_mappings.config_of(&amd64), Some(1));
+        assert_eq!(source_mappings.config_of(&dir.path().join("amd64/auto/new.cpp")), Some(1));
+        assert_eq!(source_mappings.config_of(&source), None);
+
+        let range = Range::new(Position::new(0, 0), Position::new(0, 3));
+        let mapped = source_mappings.map_range_all(ToPreprocess, source.to_str().unwrap(), &range);
+        let expected = Range::new(Position::new(2, 0), Position::new(2, 3));
+        assert_eq!(
+            mapped,
+            [
+                (arm64.to_str().unwrap().to_owned(), expected),
+                (amd64.to_str().unwrap().to_owned(), expected)
+            ]
+        );
+        let location = source_mappings.map(FromPreprocess, amd64.to_str().unwrap(), 2, 0);
+        assert_eq!((location.path, location.line), (source, 0));
+    }
+}
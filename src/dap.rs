@@ -0,0 +1,551 @@
+//! Debug Adapter Protocol proxy, translates between an editor (sees un-preprocessed Fiasco
+//! source) and a debug adapter, e.g. gdb in DAP mode (sees preprocessed Fiasco).
+//!
+//! Breakpoints set in sources are moved to the preprocessed files, locations reported by the
+//! debug adapter (stack frames, breakpoint events) are moved back to the sources.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use crossbeam_channel::select;
+use serde_json::{json, Value};
+
+use crate::global_state::Direction;
+use crate::language_server_transport::{
+    self, reader_loop, writer_loop, LanguageServerTransport, TransportMessage, Void,
+};
+use crate::source_mapping::MapDirection::ToPreprocess;
+use crate::source_mapping::{FiascoSourceMapping, MapDirection};
+use crate::thread_worker::Worker;
+use crate::websocket_logger::{self, Logger};
+
+/// A DAP message, i.e. a request, response or event.
+#[derive(Clone, Debug)]
+pub struct DapMessage(pub Value);
+
+impl TransportMessage for DapMessage {
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut size = None;
+        let mut header = String::new();
+        loop {
+            header.clear();
+            if reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header
+                .split_once(": ")
+                .ok_or_else(|| invalid(format!("Malformed DAP header: {header:?}")))?;
+            if name.eq_ignore_ascii_case("Content-Length") {
+                size = Some(value.parse::<usize>().map_err(|err| invalid(err.to_string()))?);
+            }
+        }
+        let size = size.ok_or_else(|| invalid("Missing Content-Length header".to_owned()))?;
+        let mut body = vec![0; size];
+        reader.read_exact(&mut body)?;
+        let value = serde_json::from_slice(&body)
+            .map_err(|err| invalid(format!("Malformed DAP payload: {err}")))?;
+        Ok(Some(DapMessage(value)))
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let text = serde_json::to_string(&self.0)?;
+        write!(writer, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        writer.flush()
+    }
+}
+
+impl DapMessage {
+    fn kind(&self) -> &str {
+        self.0["type"].as_str().unwrap_or_default()
+    }
+
+    fn seq(&self) -> i64 {
+        self.0["seq"].as_i64().unwrap_or_default()
+    }
+
+    /// The command of a request or response, or the event of an event.
+    fn name(&self) -> &str {
+        match self.kind() {
+            "event" => self.0["event"].as_str(),
+            _ => self.0["command"].as_str(),
+        }
+        .unwrap_or_default()
+    }
+}
+
+/// Request forwarded to the debug adapter, by the sequence number the proxy assigned.
+enum PendingRequest {
+    Forward {
+        client_seq: i64,
+    },
+    /// One of the `setBreakpoints` requests a client `setBreakpoints` request was split into, one
+    /// per preprocessed file. `indices` refers for each breakpoint of the forwarded request to
+    /// the breakpoint of the client request, or is `None` for a breakpoint of another source
+    /// file mapped to the same preprocessed file.
+    SetBreakpoints {
+        client_seq: i64,
+        indices: Vec<Option<usize>>,
+    },
+}
+
+/// Collects the responses of the `setBreakpoints` requests a client request was split into.
+struct BreakpointsResponse {
+    source: Value,
+    results: Vec<Option<Value>>,
+    pending: usize,
+    /// Messages of the responses that failed, the client request fails then too.
+    errors: Vec<String>,
+}
+
+struct DapProxy {
+    client_in: Worker<Void, DapMessage>,
+    client_out: Worker<DapMessage, Void>,
+    adapter: LanguageServerTransport<DapMessage>,
+    logger: Logger,
+    source_mapping: FiascoSourceMapping,
+    /// Whether lines/columns are one-based, as negotiated by the `initialize` request.
+    line_base: u32,
+    column_base: u32,
+    next_adapter_seq: i64,
+    next_client_seq: i64,
+    adapter_reqs: HashMap<i64, PendingRequest>,
+    /// Requests of the debug adapter (reverse requests) forwarded to the client, by the
+    /// sequence number the proxy assigned, to the sequence number of the debug adapter.
+    client_reqs: HashMap<i64, i64>,
+    breakpoint_responses: HashMap<i64, BreakpointsResponse>,
+    /// The breakpoints set for each source file, by preprocessed file, with their index in the
+    /// `setBreakpoints` request of the source file.
+    breakpoints: HashMap<PathBuf, HashMap<PathBuf, Vec<(usize, Value)>>>,
+}
+
+impl DapProxy {
+    fn send_to_adapter(&mut self, mut msg: Value) {
+        msg["seq"] = self.next_adapter_seq.into();
+        self.next_adapter_seq += 1;
+        let msg = DapMessage(msg);
+        let method = format!("{}/{}", msg.kind(), msg.name());
+        if let Err(err) = self.logger.send_json(Direction::ToServer, &method, &msg.0) {
+            debug!("{:#}", err);
+        }
+        self.adapter.to_lang_server.sender().send(msg).expect("Lost connection to debug adapter.");
+    }
+
+    fn send_to_client(&mut self, mut msg: Value) {
+        msg["seq"] = self.next_client_seq.into();
+        self.next_client_seq += 1;
+        self.client_out.sender().send(DapMessage(msg)).expect("Lost connection to client.");
+    }
+
+    fn handle_client_message(&mut self, msg: DapMessage) {
+        match msg.kind() {
+            "request" => self.handle_client_request(msg),
+            "response" => {
+                // Response to a reverse request.
+                let mut msg = msg.0;
+                let request_seq = msg["request_seq"].as_i64().unwrap_or_default();
+                match self.client_reqs.remove(&request_seq) {
+                    Some(adapter_seq) => {
+                        msg["request_seq"] = adapter_seq.into();
+                        self.send_to_adapter(msg);
+                    }
+                    None => warn!("Response to unknown request {} from client.", request_seq),
+                }
+            }
+            _ => self.send_to_adapter(msg.0),
+        }
+    }
+
+    fn handle_client_request(&mut self, msg: DapMessage) {
+        let client_seq = msg.seq();
+        let mut msg = msg.0;
+        let command = msg["command"].as_str().unwrap_or_default().to_owned();
+        if command == "setBreakpoints" && self.set_breakpoints(client_seq, &msg) {
+            return;
+        }
+        match command.as_str() {
+            "initialize" => {
+                let arguments = &msg["arguments"];
+                self.line_base = arguments["linesStartAt1"].as_bool().unwrap_or(true) as u32;
+                self.column_base = arguments["columnsStartAt1"].as_bool().unwrap_or(true) as u32;
+            }
+            "cancel" => {
+                // Refer to the sequence number used towards the debug adapter.
+                if let Some(request_id) = msg["arguments"]["requestId"].as_i64() {
+                    let adapter_seq = self.adapter_reqs.iter().find_map(|(seq, pending)| {
+                        matches!(pending, PendingRequest::Forward { client_seq }
+                            if *client_seq == request_id)
+                        .then_some(*seq)
+                    });
+                    if let Some(adapter_seq) = adapter_seq {
+                        msg["arguments"]["requestId"] = adapter_seq.into();
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.adapter_reqs.insert(self.next_adapter_seq, PendingRequest::Forward { client_seq });
+        self.send_to_adapter(msg);
+    }
+
+    /// Splits a `setBreakpoints` request of a source file into requests for the preprocessed
+    /// files the breakpoints map to. As a request replaces all breakpoints of a file, each
+    /// request contains the breakpoints of all source files mapped to that preprocessed file.
+    /// Returns false if the source file is not mapped at all.
+    fn set_breakpoints(&mut self, client_seq: i64, msg: &Value) -> bool {
+        let arguments = &msg["arguments"];
+        let Some(source_path) = arguments["source"]["path"].as_str() else {
+            return false;
+        };
+        let source = PathBuf::from(source_path);
+        if !self.source_mapping.files(ToPreprocess).any(|file| file == source) {
+            return false;
+        }
+
+        let breakpoints: Vec<Value> = match arguments["breakpoints"].as_array() {
+            Some(breakpoints) => breakpoints.clone(),
+            // Deprecated form, only with line numbers.
+            None => arguments["lines"]
+                .as_array()
+                .map(|lines| lines.iter().map(|line| json!({ "line": line })).collect())
+                .unwrap_or_default(),
+        };
+        let mut targets: HashMap<PathBuf, Vec<(usize, Value)>> = HashMap::new();
+        for (index, breakpoint) in breakpoints.iter().enumerate() {
+            let line = breakpoint["line"].as_u64().unwrap_or_default() as u32;
+            let column = breakpoint["column"].as_u64().map(|column| column as u32);
+            let mapped = self.source_mapping.map_all(
+                ToPreprocess,
+                source_path,
+                line.saturating_sub(self.line_base),
+                column.unwrap_or(self.column_base).saturating_sub(self.column_base),
+            );
+            for (location, _) in mapped {
+                let mut breakpoint = breakpoint.clone();
+                breakpoint["line"] = (location.line + self.line_base).into();
+                if column.is_some() {
+                    breakpoint["column"] = (location.character + self.column_base).into();
+                }
+                targets.entry(location.path).or_default().push((index, breakpoint));
+            }
+        }
+
+        let old_targets = self.breakpoints.insert(source.clone(), targets).unwrap_or_default();
+        let affected: BTreeSet<PathBuf> =
+            old_targets.into_keys().chain(self.breakpoints[&source].keys().cloned()).collect();
+        self.breakpoint_responses.insert(
+            client_seq,
+            BreakpointsResponse {
+                source: arguments["source"].clone(),
+                results: vec![None; breakpoints.len()],
+                pending: affected.len(),
+                errors: Vec::new(),
+            },
+        );
+
+        for target in &affected {
+            let mut indices = Vec::new();
+            let mut target_breakpoints = Vec::new();
+            for (file, file_targets) in &self.breakpoints {
+                for (index, breakpoint) in file_targets.get(target).into_iter().flatten() {
+                    indices.push((*file == source).then_some(*index));
+                    target_breakpoints.push(breakpoint.clone());
+                }
+            }
+            let name = target.file_name().map(|name| name.to_string_lossy().into_owned());
+            let mut request = json!({
+                "type": "request",
+                "command": "setBreakpoints",
+                "arguments": {
+                    "source": { "name": name, "path": target },
+                    "lines": target_breakpoints.iter().map(|b| b["line"].clone()).collect::<Vec<_>>(),
+                    "breakpoints": target_breakpoints,
+                },
+            });
+            if let Some(modified) = arguments.get("sourceModified") {
+                request["arguments"]["sourceModified"] = modified.clone();
+            }
+            self.adapter_reqs.insert(
+                self.next_adapter_seq,
+                PendingRequest::SetBreakpoints { client_seq, indices },
+            );
+            self.send_to_adapter(request);
+        }
+        if affected.is_empty() {
+            self.finish_breakpoints(client_seq);
+        }
+        true
+    }
+
+    fn handle_breakpoints_response(
+        &mut self,
+        client_seq: i64,
+        indices: Vec<Option<usize>>,
+        msg: Value,
+    ) {
+        let success = msg["success"].as_bool().unwrap_or(false);
+        let breakpoints = msg["body"]["breakpoints"].as_array().cloned().unwrap_or_default();
+        let Some(response) = self.breakpoint_responses.get_mut(&client_seq) else {
+            return;
+        };
+        if !success {
+            let message = msg["message"].as_str().unwrap_or("setBreakpoints failed").to_owned();
+            for index in indices.iter().flatten() {
+                response.results[*index].get_or_insert_with(
+                    || json!({ "verified": false, "source": response.source, "message": message }),
+                );
+            }
+            if !response.errors.contains(&message) {
+                response.errors.push(message);
+            }
+        }
+        for (index, breakpoint) in indices.iter().zip(breakpoints) {
+            let Some(index) = index else {
+                continue;
+            };
+            let mut breakpoint = breakpoint;
+            map_location(&self.source_mapping, self.line_base, self.column_base, &mut breakpoint);
+            // Prefer a verified breakpoint, if the source line maps to multiple locations.
+            let result = &mut response.results[*index];
+            if result.is_none() || (success && breakpoint["verified"].as_bool() == Some(true)) {
+                *result = Some(breakpoint);
+            }
+        }
+        response.pending -= 1;
+        if response.pending == 0 {
+            self.finish_breakpoints(client_seq);
+        }
+    }
+
+    fn finish_breakpoints(&mut self, client_seq: i64) {
+        let Some(response) = self.breakpoint_responses.remove(&client_seq) else {
+            return;
+        };
+        let breakpoints: Vec<Value> = response
+            .results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    json!({
+                        "verified": false,
+                        "source": response.source,
+                        "message": "Line is not mapped to a preprocessed file.",
+                    })
+                })
+            })
+            .collect();
+        let mut res = json!({
+            "type": "response",
+            "request_seq": client_seq,
+            "success": response.errors.is_empty(),
+            "command": "setBreakpoints",
+            "body": { "breakpoints": breakpoints },
+        });
+        if !response.errors.is_empty() {
+            res["message"] = response.errors.join("\n").into();
+        }
+        self.send_to_client(res);
+    }
+
+    fn handle_adapter_message(&mut self, msg: DapMessage) {
+        let method = format!("{}/{}", msg.kind(), msg.name());
+        if let Err(err) = self.logger.send_json(Direction::FromServer, &method, &msg.0) {
+            debug!("{:#}", err);
+        }
+        let adapter_seq = msg.seq();
+        let mut msg = msg.0;
+        match msg["type"].as_str().unwrap_or_default() {
+            "response" => {
+                let request_seq = msg["request_seq"].as_i64().unwrap_or_default();
+                match self.adapter_reqs.remove(&request_seq) {
+                    Some(PendingRequest::Forward { client_seq }) => {
+                        if msg["command"] == "stackTrace" {
+                            self.map_stack_trace(&mut msg);
+                        }
+                        msg["request_seq"] = client_seq.into();
+                        self.send_to_client(msg);
+                    }
+                    Some(PendingRequest::SetBreakpoints { client_seq, indices }) => {
+                        self.handle_breakpoints_response(client_seq, indices, msg)
+                    }
+                    None => {
+                        warn!("Response to unknown request {} from debug adapter.", request_seq)
+                    }
+                }
+            }
+            "request" => {
+                self.client_reqs.insert(self.next_client_seq, adapter_seq);
+                self.send_to_client(msg);
+            }
+            _ => {
+                if msg["event"] == "breakpoint" {
+                    map_location(
+                        &self.source_mapping,
+                        self.line_base,
+                        self.column_base,
+                        &mut msg["body"]["breakpoint"],
+                    );
+                }
+                self.send_to_client(msg);
+            }
+        }
+    }
+
+    fn map_stack_trace(&self, msg: &mut Value) {
+        if let Some(frames) = msg["body"]["stackFrames"].as_array_mut() {
+            for frame in frames {
+                map_location(&self.source_mapping, self.line_base, self.column_base, frame);
+            }
+        }
+    }
+}
+
+/// Maps the `source`, `line`, `column`, `endLine` and `endColumn` properties of a stack frame or
+/// breakpoint from a preprocessed file to the source file.
+fn map_location(
+    source_mapping: &FiascoSourceMapping,
+    line_base: u32,
+    column_base: u32,
+    obj: &mut Value,
+) {
+    let Some(path) = obj["source"]["path"].as_str().map(str::to_owned) else {
+        return;
+    };
+    if !source_mapping.contains_file(Path::new(&path)) {
+        return;
+    }
+
+    let map = |line: &Value, column: &Value| {
+        let line = line.as_u64()? as u32;
+        let column = column.as_u64().unwrap_or(column_base as u64) as u32;
+        let mapped = source_mapping.map_all(
+            MapDirection::FromPreprocess,
+            &path,
+            line.saturating_sub(line_base),
+            column.saturating_sub(column_base),
+        );
+        mapped.into_iter().next().map(|(location, _)| location)
+    };
+    let Some(start) = map(&obj["line"], &obj["column"]) else {
+        return;
+    };
+    let end = map(&obj["endLine"], &obj["endColumn"]);
+
+    obj["source"]["path"] = start.path.to_string_lossy().into_owned().into();
+    if let Some(name) = start.path.file_name() {
+        obj["source"]["name"] = name.to_string_lossy().into_owned().into();
+    }
+    obj["line"] = (start.line + line_base).into();
+    if obj.get("column").is_some() {
+        obj["column"] = (start.character + column_base).into();
+    }
+    if let Some(end) = end.filter(|end| end.path == start.path) {
+        obj["endLine"] = (end.line + line_base).into();
+        if obj.get("endColumn").is_some() {
+            obj["endColumn"] = (end.character + column_base).into();
+        }
+    }
+}
+
+/// Runs the DAP proxy between the client on stdin/stdout and the given debug adapter command.
+pub fn run(source_mapping: FiascoSourceMapping, adapter: &[String]) -> Result<()> {
+    let logger = Logger::spawn(websocket_logger::DEFAULT_PORT);
+    let args: Vec<&str> = adapter[1..].iter().map(String::as_str).collect();
+    let adapter = language_server_transport::start::<DapMessage>(&adapter[0], &args, &[])?;
+
+    let client_in = Worker::spawn("Messages from debug client", 1024, |receiver, sender| {
+        if let Err(err) = reader_loop(BufReader::new(io::stdin()), receiver, &sender) {
+            error!("{}", err);
+        }
+    });
+    let client_out = Worker::spawn("Messages to debug client", 1024, |receiver, _| {
+        if let Err(err) = writer_loop(io::stdout(), &receiver) {
+            error!("{}", err);
+        }
+    });
+
+    let mut proxy = DapProxy {
+        client_in,
+        client_out,
+        adapter,
+        logger,
+        source_mapping,
+        line_base: 1,
+        column_base: 1,
+        next_adapter_seq: 1,
+        next_client_seq: 1,
+        adapter_reqs: HashMap::new(),
+        client_reqs: HashMap::new(),
+        breakpoint_responses: HashMap::new(),
+        breakpoints: HashMap::new(),
+    };
+
+    loop {
+        select! {
+            recv(proxy.client_in.receiver()) -> r => match r {
+                Ok(msg) => proxy.handle_client_message(msg),
+                Err(_) => {
+                    info!("Debug client disconnected.");
+                    return Ok(());
+                }
+            },
+            recv(proxy.adapter.stderr.receiver()) -> r => {
+                if let Ok(line) = r {
+                    info!("Debug adapter: {}", line);
+                }
+            },
+            recv(proxy.adapter.from_lang_server.receiver()) -> r => match r {
+                Ok(msg) => proxy.handle_adapter_message(msg),
+                Err(_) => {
+                    info!("Debug adapter terminated.");
+                    // Flush the messages to the client, but do not wait for the client to close
+                    // stdin.
+                    drop(proxy.client_out);
+                    std::process::exit(0);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::source_mapping::load_source_mapping;
+
+    #[test]
+    fn stack_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path();
+        fs::create_dir(build_dir.join("auto")).unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        let preprocessed = build_dir.join("auto/foo.cpp");
+        fs::write(&preprocessed, "// foo\n#line 10 \"/src/foo.cpp\"\nint a;\n").unwrap();
+        let (source_mapping, _) = load_source_mapping(build_dir);
+
+        let mut frame = json!({ "line": 3, "column": 5, "source": { "path": preprocessed } });
+        map_location(&source_mapping, 1, 1, &mut frame);
+        assert_eq!(
+            frame,
+            json!({
+                "line": 10,
+                "column": 5,
+                "source": { "name": "foo.cpp", "path": "/src/foo.cpp" },
+            })
+        );
+
+        let message = DapMessage(json!({ "seq": 1, "type": "event", "event": "stopped" }));
+        let mut buf = Vec::new();
+        message.write(&mut buf).unwrap();
+        let read = DapMessage::read(&mut buf.as_slice()).unwrap().unwrap();
+        assert_eq!(read.name(), "stopped");
+    }
+}
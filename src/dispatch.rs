@@ -4,8 +4,11 @@ use std::{fmt, mem};
 
 use lsp_server::RequestId;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 
+use crate::aggregation::Aggregation;
 use crate::global_state::{Direction, GlobalState, ReqContext, ReqContextAlloc};
+use crate::handler::document_split::{self, DocumentSplit};
 use crate::util::{build_notif, build_req, build_res, cast_notif, cast_req, cast_res};
 
 impl fmt::Display for Direction {
@@ -21,11 +24,20 @@ impl fmt::Display for Direction {
 pub struct RequestDispatcher<'a> {
     pub direction: Direction,
     pub req: Option<lsp_server::Request>,
+    /// Configuration of the language server that sent a request from the server. Requests from
+    /// the client are routed by their document instead.
+    pub config: usize,
     pub state: &'a mut GlobalState,
 }
 
 // TODO: Instead of ReqContextAlloc we could just return a generic value...
 
+/// Handler splitting one request into many requests, each with its own request context.
+type ManyReqHandler<P> = fn(&mut GlobalState, &ReqContextAlloc, P) -> Vec<(P, ReqContext)>;
+
+/// Handler answering a request in the proxy, returns `None` for requests it does not handle.
+type LocalReqHandler<P, R> = fn(&mut GlobalState, &P) -> Option<Result<R, String>>;
+
 impl RequestDispatcher<'_> {
     /// Dispatches the request.
     pub fn on<R>(
@@ -51,25 +63,25 @@ impl RequestDispatcher<'_> {
             Err((id, err)) => {
                 warn!("Received malformed request from {}: {}", self.direction, err);
                 self.state
-                    .send(
+                    .send_to(
                         self.direction.reverse(),
+                        self.config,
                         lsp_server::Response::new_err(
                             id,
                             lsp_server::ErrorCode::InvalidParams as i32,
                             "malformed params".to_string(),
                         ),
                     )
-                    .expect(&format!("Lost connection to {}.", self.direction.reverse()));
+                    .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction.reverse()));
             }
         };
 
         self
     }
 
-    pub fn on_many<R>(
-        &mut self,
-        f: fn(&mut GlobalState, &ReqContextAlloc, R::Params) -> Vec<(R::Params, ReqContext)>,
-    ) -> &mut Self
+    /// Dispatches a request that is split into many requests. The responses to requests from
+    /// the client are aggregated, see `ResponseDispatcher::on_collect`.
+    pub fn on_many<R>(&mut self, f: ManyReqHandler<R::Params>) -> &mut Self
     where
         R: lsp_types::request::Request,
         R::Params: DeserializeOwned,
@@ -85,7 +97,11 @@ impl RequestDispatcher<'_> {
                 let req_context_alloc =
                     ReqContextAlloc { req_method: R::METHOD.to_owned(), req_id: id.clone() };
                 // Translate request.
-                for (mapped, req_context) in f(self.state, &req_context_alloc, params) {
+                let reqs = f(self.state, &req_context_alloc, params);
+                if let Direction::ToServer = self.direction {
+                    self.state.aggregations.insert(id, Aggregation::new(reqs.len()));
+                }
+                for (mapped, req_context) in reqs {
                     let req_id = RequestId::from(self.state.alloc_req_id() as i32);
                     self.send_req(req_context, build_req::<R>(req_id, mapped));
                 }
@@ -93,21 +109,67 @@ impl RequestDispatcher<'_> {
             Err((id, err)) => {
                 warn!("Received malformed request from {}: {}", self.direction, err);
                 self.state
-                    .send(
+                    .send_to(
                         self.direction.reverse(),
+                        self.config,
                         lsp_server::Response::new_err(
                             id,
                             lsp_server::ErrorCode::InvalidParams as i32,
                             "malformed params".to_string(),
                         ),
                     )
-                    .expect(&format!("Lost connection to {}.", self.direction.reverse()));
+                    .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction.reverse()));
             }
         };
 
         self
     }
 
+    /// Dispatches a document request, split into one request per preprocessed file of the
+    /// document.
+    pub fn on_split<R>(&mut self) -> &mut Self
+    where
+        R: DocumentSplit,
+        R::Params: Clone,
+    {
+        self.on_many::<R>(document_split::split::<R>)
+    }
+
+    /// Handles the request in the proxy instead of sending it on, if `f` returns a result.
+    /// Otherwise the request is left to the following handlers.
+    pub fn on_local<R>(&mut self, f: LocalReqHandler<R::Params, R::Result>) -> &mut Self
+    where
+        R: lsp_types::request::Request,
+        R::Params: DeserializeOwned,
+    {
+        let params = match &self.req {
+            Some(req) if req.method == R::METHOD => req.params.clone(),
+            _ => return self,
+        };
+        // Malformed params are reported by the following handlers.
+        let Ok(params) = serde_json::from_value(params) else {
+            return self;
+        };
+        let Some(result) = f(self.state, &params) else {
+            return self;
+        };
+
+        let id = self.req.take().unwrap().id;
+        let res = match result {
+            Ok(result) => build_res(id, result),
+            Err(message) => lsp_server::Response::new_err(
+                id,
+                lsp_server::ErrorCode::RequestFailed as i32,
+                message,
+            ),
+        };
+        self.state
+            .send(self.direction.reverse(), res)
+            .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction.reverse()));
+
+        self
+    }
+
     pub fn forward<R>(&mut self) -> &mut Self
     where
         R: lsp_types::request::Request,
@@ -141,13 +203,23 @@ impl RequestDispatcher<'_> {
         self.prepare_req_id(&req.method, &mut req.id)
     }
 
-    fn send_req(&mut self, req_context: ReqContext, req: lsp_server::Request) {
+    /// Sends a request to the language server of the preprocessed file it refers to, see
+    /// `GlobalState::route`. Other requests go to the configuration the handler set in the
+    /// request context, the primary one by default.
+    fn send_req(&mut self, mut req_context: ReqContext, req: lsp_server::Request) {
+        let config = match self.direction {
+            Direction::ToServer => {
+                self.state.route(&req.params).unwrap_or_else(|| req_context.config())
+            }
+            Direction::FromServer => self.config,
+        };
+        req_context.set_config(config);
         // Register request as pending.
         self.state.reqs(self.direction).insert(req.id.clone(), req_context);
         // Send request.
         self.state
-            .send(self.direction, req)
-            .expect(&format!("Lost connection to {}.", self.direction));
+            .send_to(self.direction, config, req)
+            .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction));
     }
 }
 
@@ -167,6 +239,11 @@ impl<'a> ResponseDispatcher<'a> {
     ) -> Self {
         // Lookup and remove request type for id.
         let req_context = state.reqs(direction.reverse()).remove(&res.id);
+        if req_context.as_ref().is_some_and(ReqContext::cancelled) {
+            // The request was already answered when it was cancelled.
+            debug!("Drop response to cancelled request from {}: {:?}", direction, res);
+            return Self { state, direction, res: None, req_context: None };
+        }
         Self { state, direction, res: Some(res), req_context }
     }
 
@@ -212,13 +289,18 @@ impl<'a> ResponseDispatcher<'a> {
         self
     }
 
+    /// Dispatches the response to one of the requests a client request was split into by
+    /// `RequestDispatcher::on_many`. Each result is mapped by `f`, the client request is answered
+    /// once all responses are in, with the results merged by `merge`. Errors and malformed
+    /// responses only fail the client request if none of the requests succeeded.
     pub fn on_collect<R>(
         &mut self,
-        f: fn(&mut GlobalState, &mut ReqContext, R::Result) -> Option<R::Result>,
+        f: fn(&mut GlobalState, &mut ReqContext, R::Result) -> R::Result,
+        merge: fn(Vec<R::Result>) -> R::Result,
     ) -> &mut Self
     where
         R: lsp_types::request::Request,
-        R::Result: DeserializeOwned,
+        R::Result: DeserializeOwned + Serialize + 'static,
     {
         if self.req_context.is_none() {
             // Unexpected response (no corresponding request registered), we
@@ -232,39 +314,52 @@ impl<'a> ResponseDispatcher<'a> {
             _ => return self,
         };
 
-        // TODO: Ignore / collect / forward errors? Maybe use a trait instead?
-        let orig_req_id = req_context.req_id().clone();
-        if res.error.is_some() {
-            //panic!("Error on collect!");
-            self.send_res(lsp_server::Response { id: orig_req_id, result: None, error: res.error });
-            return self;
-        }
+        let result = match res.error {
+            Some(error) => Err(error),
+            None => match cast_res::<R>(res) {
+                Ok((_id, params)) => Ok(f(self.state, req_context, params)),
+                Err(err) => {
+                    warn!("Received malformed response from {}: {}", self.direction, err);
+                    Err(lsp_server::ResponseError {
+                        code: lsp_server::ErrorCode::InternalError as i32,
+                        message: format!("Malformed response: {}", err),
+                        data: None,
+                    })
+                }
+            },
+        };
 
-        match cast_res::<R>(res) {
-            Ok((_id, params)) => {
-                // Translate response.
-                let mapped_opt = f(self.state, req_context, params);
-                if let Some(mapped) = mapped_opt {
-                    self.send_res(build_res(orig_req_id, mapped));
+        let orig_req_id = req_context.req_id().clone();
+        match self.state.aggregations.get_mut(&orig_req_id) {
+            Some(aggregation) => {
+                match result {
+                    Ok(mapped) => aggregation.add_result(mapped, merge),
+                    Err(error) => aggregation.add_error(error),
                 }
+                self.state.answer_aggregation(&orig_req_id);
             }
-            Err(err) => {
-                panic!("Received malformed response from {}: {}", self.direction, err);
-                // TODO: Can / have we to report something to the sender?
-                //       We have to count down Rc<> reference counter!
-            }
-        };
+            // The request was not split, e.g. it came from a language server.
+            None => match result {
+                Ok(mapped) => self.send_res(build_res(orig_req_id, mapped)),
+                Err(error) => self.send_res(lsp_server::Response {
+                    id: orig_req_id,
+                    result: None,
+                    error: Some(error),
+                }),
+            },
+        }
 
         self
     }
 
-    // There are many requests that take a document (+ optional range) as parameter and returns a vector of result objects.
-    // Because on <non-preprocessed>.cpp is mapped to multiple files, for all this requests we need to do split and merge!
-    // A generic abstraction in dispatch for that is therefore justified!
-
-    // on_many:
-    //  - need to ignore/remember/join errors
-    //  - only send response once last response came in
+    /// Dispatches the response to one of the requests a document request was split into.
+    pub fn on_split<R>(&mut self) -> &mut Self
+    where
+        R: DocumentSplit,
+        R::Result: 'static,
+    {
+        self.on_collect::<R>(document_split::map::<R>, document_split::merge::<R>)
+    }
 
     /// Dispatches the response.
     pub fn forward<R>(&mut self) -> &mut Self
@@ -309,12 +404,13 @@ impl<'a> ResponseDispatcher<'a> {
     }
 
     fn send_res(&mut self, mut res: lsp_server::Response) {
+        let req_context = self.req_context.as_ref().unwrap();
         // Restore original request id.
-        res.id = self.req_context.as_ref().unwrap().req_id().clone();
-        // Send response.
+        res.id = req_context.req_id().clone();
+        // Send response, to the language server that sent the request.
         self.state
-            .send(self.direction, res)
-            .expect(&format!("Lost connection to {}.", self.direction));
+            .send_to(self.direction, req_context.config(), res)
+            .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction));
     }
 }
 
@@ -322,9 +418,15 @@ impl<'a> ResponseDispatcher<'a> {
 pub struct NotificationDispatcher<'a> {
     pub direction: Direction,
     pub not: Option<lsp_server::Notification>,
+    /// Configuration of the language server that sent a notification from the server.
+    pub config: usize,
     pub state: &'a mut GlobalState,
 }
 
+/// Handler consuming a notification in the proxy, gets the direction and the configuration of
+/// the notification.
+type LocalNotHandler<P> = fn(&mut GlobalState, Direction, usize, P);
+
 impl NotificationDispatcher<'_> {
     fn _on<N, S>(
         &mut self,
@@ -370,6 +472,27 @@ impl NotificationDispatcher<'_> {
         self._on::<N, Vec<N::Params>>(Self::send_many::<N>, f)
     }
 
+    /// Handles the notification in the proxy instead of sending it on.
+    pub fn on_local<N>(&mut self, f: LocalNotHandler<N::Params>) -> &mut Self
+    where
+        N: lsp_types::notification::Notification,
+        N::Params: DeserializeOwned,
+    {
+        let not = match &self.not {
+            Some(not) if not.method == N::METHOD => self.not.take().unwrap(),
+            _ => return self,
+        };
+
+        match cast_notif::<N>(not) {
+            Ok(params) => f(self.state, self.direction, self.config, params),
+            Err(err) => {
+                warn!("Received malformed notification from {}: {}", self.direction, err);
+            }
+        };
+
+        self
+    }
+
     /// Dispatches the request.
     pub fn forward<N>(&mut self) -> &mut Self
     where
@@ -384,7 +507,7 @@ impl NotificationDispatcher<'_> {
         // Forward
         self.state
             .send(self.direction, not)
-            .expect(&format!("Lost connection to {}.", self.direction));
+            .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction));
 
         self
     }
@@ -394,7 +517,7 @@ impl NotificationDispatcher<'_> {
             warn!("Unhandled notification: {:?}", not);
             self.state
                 .send(self.direction, not)
-                .expect(&format!("Lost connection to {}.", self.direction));
+                .unwrap_or_else(|_| panic!("Lost connection to {}.", self.direction));
         }
     }
 
@@ -405,7 +528,7 @@ impl NotificationDispatcher<'_> {
     {
         state
             .send(direction, build_notif::<N>(params))
-            .expect(&format!("Lost connection to {}.", direction))
+            .unwrap_or_else(|_| panic!("Lost connection to {}.", direction))
     }
 
     fn send_many<N>(state: &mut GlobalState, direction: Direction, params: Vec<N::Params>)
@@ -0,0 +1,195 @@
+//! Validates the line mappings of a build directory, to tell apart problems of the line mapping
+//! from problems of the language server.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::source_mapping::MapDirection::{FromPreprocess, ToPreprocess};
+use crate::source_mapping::{
+    load_modules, load_source_mapping, FiascoSourceMapping, LoadReport, SourceLocation,
+};
+
+/// A source line whose mapping to a preprocessed file does not map back to it.
+#[derive(Debug, Serialize)]
+pub struct RoundTripError {
+    /// Zero-based source line.
+    pub line: u32,
+    /// Location in the preprocessed file the line was mapped to.
+    pub mapped: SourceLocation,
+    /// Location the preprocessed location maps back to, if any.
+    pub returned: Option<SourceLocation>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FileReport {
+    /// Ranges of zero-based source lines (inclusive) that are not mapped to any preprocessed
+    /// file. Lines dropped by preprocess, e.g. of sections not in the configuration, are expected
+    /// to show up here.
+    pub unmapped_lines: Vec<(u32, u32)>,
+    pub round_trip_errors: Vec<RoundTripError>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DoctorReport {
+    pub files: BTreeMap<PathBuf, FileReport>,
+    /// Source files of the modules that are not mapped to any preprocessed file.
+    pub unmapped_files: Vec<PathBuf>,
+    /// Problems found while loading the mappings, including overlaps.
+    pub load: LoadReport,
+}
+
+impl DoctorReport {
+    pub fn round_trip_errors(&self) -> usize {
+        self.files.values().map(|file| file.round_trip_errors.len()).sum()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (path, file) in &self.files {
+            if !file.unmapped_lines.is_empty() {
+                let ranges: Vec<String> = file
+                    .unmapped_lines
+                    .iter()
+                    .map(|&(start, end)| match start == end {
+                        true => format!("{}", start + 1),
+                        false => format!("{}-{}", start + 1, end + 1),
+                    })
+                    .collect();
+                writeln!(text, "{}: unmapped lines {}", path.display(), ranges.join(", ")).unwrap();
+            }
+            for error in &file.round_trip_errors {
+                let returned = match &error.returned {
+                    Some(location) => format!("{}:{}", location.path.display(), location.line + 1),
+                    None => "nothing".to_owned(),
+                };
+                writeln!(
+                    text,
+                    "{}:{}: maps to {}:{}, which maps back to {}",
+                    path.display(),
+                    error.line + 1,
+                    error.mapped.path.display(),
+                    error.mapped.line + 1,
+                    returned
+                )
+                .unwrap();
+            }
+        }
+        for path in &self.unmapped_files {
+            writeln!(text, "{}: no mapping", path.display()).unwrap();
+        }
+        for (path, problems) in &self.load.problems {
+            for problem in problems {
+                writeln!(text, "{}: {}", path.display(), problem).unwrap();
+            }
+        }
+        writeln!(
+            text,
+            "{} files checked, {} round-trip errors, {} unmapped files, {} files with problems.",
+            self.files.len(),
+            self.round_trip_errors(),
+            self.unmapped_files.len(),
+            self.load.problems.len()
+        )
+        .unwrap();
+        text
+    }
+}
+
+/// Checks every line of a source file, that its mapping to a preprocessed file maps back to the
+/// same line. Columns are not checked, as columns removed by preprocess (e.g. `PUBLIC`) cannot
+/// map back.
+fn check_file(source_mapping: &FiascoSourceMapping, path: &Path) -> FileReport {
+    let source = path.to_str().unwrap();
+    let length = match fs::read_to_string(path) {
+        Ok(text) => text.lines().count() as u32,
+        Err(_) => source_mapping.file_length(ToPreprocess, path).unwrap_or(0) + 1,
+    };
+
+    let mut report = FileReport::default();
+    for line in 0..length {
+        let mapped = source_mapping.map_all(ToPreprocess, source, line, 0);
+        if mapped.is_empty() {
+            match report.unmapped_lines.last_mut() {
+                Some((_, end)) if *end + 1 == line => *end = line,
+                _ => report.unmapped_lines.push((line, line)),
+            }
+        }
+        for (location, _) in mapped {
+            let back = source_mapping.map_all(
+                FromPreprocess,
+                location.path.to_str().unwrap(),
+                location.line,
+                0,
+            );
+            if !back.iter().any(|(back, _)| back.path == path && back.line == line) {
+                report.round_trip_errors.push(RoundTripError {
+                    line,
+                    mapped: location,
+                    returned: back.into_iter().next().map(|(back, _)| back),
+                });
+            }
+        }
+    }
+    report
+}
+
+/// Returns the source files of the modules of the build directory, that none of the preprocessed
+/// files map to.
+fn unmapped_files(source_mapping: &FiascoSourceMapping, build_dir: &Path) -> Vec<PathBuf> {
+    let mapped: BTreeSet<PathBuf> = source_mapping
+        .files(ToPreprocess)
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+        .collect();
+    let sources: BTreeSet<PathBuf> = load_modules(build_dir.to_str().unwrap())
+        .into_values()
+        .flatten()
+        .map(|dep| build_dir.join(dep))
+        .collect();
+    sources
+        .into_iter()
+        .filter(|path| !mapped.contains(&path.canonicalize().unwrap_or_else(|_| path.clone())))
+        .collect()
+}
+
+/// Loads the line mappings of the build directory and checks them.
+pub fn run(build_dir: &Path) -> DoctorReport {
+    let (source_mapping, load) = load_source_mapping(build_dir);
+    let files = source_mapping
+        .files(ToPreprocess)
+        .map(|path| (path.to_path_buf(), check_file(&source_mapping, path)))
+        .collect();
+    DoctorReport { files, unmapped_files: unmapped_files(&source_mapping, build_dir), load }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path();
+        let auto = build_dir.join("auto");
+        fs::create_dir(&auto).unwrap();
+        let source = build_dir.join("foo.cpp");
+        fs::write(&source, "INTERFACE:\nclass Foo {};\nIMPLEMENTATION:\nint a;\nint b;\n").unwrap();
+        fs::write(build_dir.join("bar.cpp"), "").unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp bar.cpp\n")
+            .unwrap();
+        let line = |line: u32| format!("#line {} \"{}\"\n", line, source.display());
+        fs::write(auto.join("foo.h"), format!("{}class Foo {{}};\n#endif // foo_h\n", line(2)))
+            .unwrap();
+        fs::write(auto.join("foo.cpp"), format!("{}int a;\nint b;\n", line(4))).unwrap();
+
+        let report = run(build_dir);
+        let file = &report.files[&source];
+        assert_eq!(file.unmapped_lines, [(0, 0), (2, 2)]);
+        assert!(file.round_trip_errors.is_empty());
+        assert_eq!(report.unmapped_files, [build_dir.join("bar.cpp")]);
+        assert!(report.load.is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+//! Rewrites the locations in build output, e.g. compiler diagnostics, from preprocessed files
+//! back to the Fiasco sources.
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use lazy_static::lazy_static;
+use lsp_types::Url;
+use regex::{bytes, Regex};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::map::resolve_path;
+use crate::source_mapping::MapDirection::FromPreprocess;
+use crate::source_mapping::{FiascoSourceMapping, SourceLocation};
+
+lazy_static! {
+    /// A `path:line:` or `path:line:col:` reference. Includes also `path:line,` as printed by gcc
+    /// in "In file included from" lines. Matches bytes, as the rest of the line might not be
+    /// UTF-8, e.g. a source snippet in another encoding.
+    static ref LOCATION_RE: bytes::Regex =
+        bytes::Regex::new(r#"([^\s:'"()]+):(\d+)(?::(\d+))?([:,])"#).unwrap();
+    /// A diagnostic of gcc or clang.
+    static ref DIAGNOSTIC_RE: Regex = Regex::new(
+        r"^([^\s:]+):(\d+):(?:(\d+):)? (fatal error|error|warning|note): (.*)$"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// The build output with rewritten locations.
+    Text,
+    /// The diagnostics as JSON.
+    Json,
+    /// The diagnostics as SARIF 2.1.0.
+    Sarif,
+}
+
+/// Diagnostic found in the build output. Lines and columns are one-based.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub severity: String,
+    pub message: String,
+    /// Location in the preprocessed file, if the location was mapped.
+    pub preprocessed: Option<PreprocessedLocation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreprocessedLocation {
+    pub file: PathBuf,
+    pub line: u32,
+    pub column: Option<u32>,
+}
+
+struct LogFilter<'a> {
+    source_mapping: &'a FiascoSourceMapping,
+    build_dir: &'a Path,
+}
+
+impl LogFilter<'_> {
+    /// Maps a one-based location of a preprocessed file. Returns `None` if the file is not a
+    /// preprocessed file. Relative paths are relative to the build directory, as make runs there.
+    fn map(&self, path: &str, line: u32, column: Option<u32>) -> Option<SourceLocation> {
+        let path = Path::new(path);
+        let joined = self.build_dir.join(path);
+        let path = match self.source_mapping.contains_file(&joined) {
+            true => joined,
+            false => resolve_path(self.source_mapping, FromPreprocess, path),
+        };
+        if !self.source_mapping.contains_file(&path) {
+            return None;
+        }
+        let character = column.unwrap_or(1).saturating_sub(1);
+        let mapped = self.source_mapping.map_all(
+            FromPreprocess,
+            path.to_str()?,
+            line.saturating_sub(1),
+            character,
+        );
+        mapped.into_iter().next().map(|(location, _)| location)
+    }
+
+    /// Rewrites the locations in a line, the other bytes of the line are kept as they are.
+    fn rewrite_line(&self, line: &[u8]) -> Vec<u8> {
+        LOCATION_RE
+            .replace_all(line, |cap: &bytes::Captures| {
+                // The groups of the path, line and column only match UTF-8.
+                let group = |i| cap.get(i).and_then(|m| std::str::from_utf8(m.as_bytes()).ok());
+                let column = group(3).and_then(|col| col.parse().ok());
+                let mapped = group(2)
+                    .and_then(|line| line.parse().ok())
+                    .and_then(|line| self.map(group(1)?, line, column));
+                match mapped {
+                    Some(location) => {
+                        let mut text = format!("{}:{}", location.path.display(), location.line + 1);
+                        if column.is_some() {
+                            text += &format!(":{}", location.character + 1);
+                        }
+                        let mut text = text.into_bytes();
+                        text.extend_from_slice(&cap[4]);
+                        text
+                    }
+                    None => cap[0].to_vec(),
+                }
+            })
+            .into_owned()
+    }
+
+    fn parse_diagnostic(&self, line: &str) -> Option<Diagnostic> {
+        let cap = DIAGNOSTIC_RE.captures(line)?;
+        let line_number: u32 = cap[2].parse().ok()?;
+        let column: Option<u32> = cap.get(3).and_then(|col| col.as_str().parse().ok());
+        let (file, line, column, preprocessed) = match self.map(&cap[1], line_number, column) {
+            Some(location) => (
+                location.path,
+                location.line + 1,
+                column.map(|_| location.character + 1),
+                Some(PreprocessedLocation { file: cap[1].into(), line: line_number, column }),
+            ),
+            None => (cap[1].into(), line_number, column, None),
+        };
+        Some(Diagnostic {
+            file,
+            line,
+            column,
+            severity: cap[4].to_owned(),
+            message: String::from_utf8_lossy(&self.rewrite_line(cap[5].as_bytes())).into_owned(),
+            preprocessed,
+        })
+    }
+}
+
+fn sarif(diagnostics: &[Diagnostic]) -> serde_json::Value {
+    let results: Vec<serde_json::Value> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let level = match diagnostic.severity.as_str() {
+                "warning" => "warning",
+                "note" => "note",
+                _ => "error",
+            };
+            let uri = match Url::from_file_path(&diagnostic.file) {
+                Ok(url) => url.to_string(),
+                Err(_) => diagnostic.file.display().to_string(),
+            };
+            let mut region = json!({ "startLine": diagnostic.line });
+            if let Some(column) = diagnostic.column {
+                region["startColumn"] = column.into();
+            }
+            json!({
+                "level": level,
+                "message": { "text": diagnostic.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": uri },
+                        "region": region,
+                    }
+                }],
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": { "driver": { "name": "fiasco-lsp", "version": env!("CARGO_PKG_VERSION") } },
+            "results": results,
+        }],
+    })
+}
+
+/// Reads build output from `input` and writes it to `output` in the given format. The text
+/// format is written line by line, so that it can be used while the build is running.
+pub fn run(
+    source_mapping: &FiascoSourceMapping,
+    build_dir: &Path,
+    format: LogFormat,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    let filter = LogFilter { source_mapping, build_dir };
+    let mut diagnostics = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if input.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match format {
+            LogFormat::Text => {
+                output.write_all(&filter.rewrite_line(line))?;
+                writeln!(output)?;
+            }
+            LogFormat::Json | LogFormat::Sarif => {
+                diagnostics.extend(filter.parse_diagnostic(&String::from_utf8_lossy(line)))
+            }
+        }
+    }
+    let report = match format {
+        LogFormat::Text => None,
+        LogFormat::Json => Some(serde_json::to_value(&diagnostics)?),
+        LogFormat::Sarif => Some(sarif(&diagnostics)),
+    };
+    if let Some(report) = report {
+        writeln!(output, "{}", serde_json::to_string_pretty(&report)?)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::source_mapping::load_source_mapping;
+
+    #[test]
+    fn rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path();
+        fs::create_dir(build_dir.join("auto")).unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        fs::write(build_dir.join("auto/foo.cpp"), "// foo\n#line 10 \"/src/foo.cpp\"\nint a;\n")
+            .unwrap();
+        let (source_mapping, _) = load_source_mapping(build_dir);
+
+        let log = "In file included from auto/foo.cpp:3,\n\
+                   auto/foo.cpp:3:5: error: 'a' redeclared, see auto/foo.cpp:3:1:\n\
+                   other.cpp:3:5: warning: unchanged\n";
+        let mut output = Vec::new();
+        run(&source_mapping, build_dir, LogFormat::Text, log.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "In file included from /src/foo.cpp:10,\n\
+             /src/foo.cpp:10:5: error: 'a' redeclared, see /src/foo.cpp:10:1:\n\
+             other.cpp:3:5: warning: unchanged\n"
+        );
+
+        let log = b"auto/foo.cpp:3:5: error: \xe4\n  3 | int \xe4;\nauto/foo.cpp:3:5: note: here\n";
+        let mut output = Vec::new();
+        run(&source_mapping, build_dir, LogFormat::Text, &log[..], &mut output).unwrap();
+        assert_eq!(
+            output,
+            b"/src/foo.cpp:10:5: error: \xe4\n  3 | int \xe4;\n/src/foo.cpp:10:5: note: here\n"
+        );
+        let mut output = Vec::new();
+        run(&source_mapping, build_dir, LogFormat::Json, &log[..], &mut output).unwrap();
+        let diagnostics: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+
+        let filter = LogFilter { source_mapping: &source_mapping, build_dir };
+        let diagnostic = filter.parse_diagnostic("auto/foo.cpp:3:5: error: foo").unwrap();
+        assert_eq!((diagnostic.file.as_path(), diagnostic.line), (Path::new("/src/foo.cpp"), 10));
+        assert_eq!(diagnostic.preprocessed.unwrap().line, 3);
+    }
+}
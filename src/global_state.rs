@@ -1,11 +1,21 @@
 use std::any::Any;
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use color_eyre::eyre::Result;
-use lsp_server::{Connection, RequestId};
+use crossbeam_channel::SendError;
+use lsp_server::{Connection, ErrorCode, Message, RequestId, Response, ResponseError};
+use lsp_types::notification::ShowMessage;
+use lsp_types::{Diagnostic, DidChangeTextDocumentParams, MessageType, ShowMessageParams, Url};
 
-use crate::language_server_transport::LanguageServerTransport;
-use crate::source_mapping::FiascoSourceMapping;
+use crate::aggregation::Aggregation;
+use crate::configuration::{Configuration, SourceMappings, PRIMARY};
+use crate::handler::recovery::HANG_TIMEOUT;
+use crate::preprocess_worker::{self, PreprocessEvent, PreprocessJob};
+use crate::settings::Settings;
+use crate::thread_worker::Worker;
+use crate::util::build_notif;
 use crate::websocket_logger::Logger;
 
 #[derive(Clone, Copy)]
@@ -23,16 +33,38 @@ impl Direction {
     }
 }
 
+/// A request that was not answered for this long expires, it is answered with an error. This
+/// applies to requests from the language servers to the client, requests from the client fail
+/// before, when their language server is restarted after `HANG_TIMEOUT`.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const _: () = assert!(HANG_TIMEOUT.as_secs() < REQUEST_TIMEOUT.as_secs());
+
+/// The response to a cancelled request is waited for this long, to drop it silently.
+const CANCELLED_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct ReqContext {
     method: String,
     /// Request id of the client request.
     req_id: RequestId,
+    /// Configuration of the language server the request was sent to, or received from.
+    config: usize,
+    /// When the request was received.
+    received: Instant,
+    /// When the request was cancelled, its response is dropped.
+    cancelled: Option<Instant>,
     value: Option<Box<dyn Any>>,
 }
 
 impl ReqContext {
     pub fn new(method: String, req_id: RequestId) -> Self {
-        Self { method, req_id, value: None }
+        Self {
+            method,
+            req_id,
+            config: PRIMARY,
+            received: Instant::now(),
+            cancelled: None,
+            value: None,
+        }
     }
 
     pub fn method(&self) -> &str {
@@ -43,6 +75,50 @@ impl ReqContext {
         &self.req_id
     }
 
+    pub fn config(&self) -> usize {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: usize) {
+        self.config = config;
+    }
+
+    /// Returns how long the request is pending.
+    pub fn age(&self) -> Duration {
+        self.age_at(Instant::now())
+    }
+
+    pub fn age_at(&self, now: Instant) -> Duration {
+        now.duration_since(self.received)
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.is_some()
+    }
+
+    pub fn cancel(&mut self) {
+        self.cancelled.get_or_insert_with(Instant::now);
+    }
+
+    /// Returns whether the request is pending for too long, see `REQUEST_TIMEOUT`.
+    pub fn expired(&self) -> bool {
+        self.expired_at(Instant::now())
+    }
+
+    fn expired_at(&self, now: Instant) -> bool {
+        !self.cancelled() && now.duration_since(self.received) > REQUEST_TIMEOUT
+    }
+
+    /// Returns whether the request was cancelled long ago, so that its response is not waited
+    /// for anymore.
+    pub fn forgotten(&self) -> bool {
+        self.forgotten_at(Instant::now())
+    }
+
+    fn forgotten_at(&self, now: Instant) -> bool {
+        self.cancelled.is_some_and(|cancelled| now.duration_since(cancelled) > CANCELLED_TIMEOUT)
+    }
+
     pub fn set_value<T: Any>(&mut self, value: T) {
         self.value.replace(Box::new(value));
     }
@@ -52,7 +128,66 @@ impl ReqContext {
     }
 }
 
-type RequestRegistry = HashMap<RequestId, ReqContext>;
+/// Pending requests by the id they were sent with. Remembers the ids each original request was
+/// sent with, as requests can be split into many.
+#[derive(Default)]
+pub struct RequestRegistry {
+    reqs: HashMap<RequestId, ReqContext>,
+    /// Ids the requests were sent with, by the id of the original request.
+    sent_ids: HashMap<RequestId, Vec<RequestId>>,
+}
+
+impl RequestRegistry {
+    pub fn insert(&mut self, id: RequestId, req_context: ReqContext) {
+        self.sent_ids.entry(req_context.req_id.clone()).or_default().push(id.clone());
+        self.reqs.insert(id, req_context);
+    }
+
+    pub fn remove(&mut self, id: &RequestId) -> Option<ReqContext> {
+        let req_context = self.reqs.remove(id)?;
+        self.forget_sent_id(&req_context.req_id, id);
+        Some(req_context)
+    }
+
+    fn forget_sent_id(&mut self, req_id: &RequestId, id: &RequestId) {
+        if let Some(ids) = self.sent_ids.get_mut(req_id) {
+            ids.retain(|sent_id| sent_id != id);
+            if ids.is_empty() {
+                self.sent_ids.remove(req_id);
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, id: &RequestId) -> Option<&mut ReqContext> {
+        self.reqs.get_mut(id)
+    }
+
+    /// Returns the ids the request with the given original id was sent with. Ids of requests
+    /// from different language servers can collide, see `ReqContext::config` to tell them apart.
+    pub fn sent_ids(&self, req_id: &RequestId) -> Vec<RequestId> {
+        self.sent_ids.get(req_id).cloned().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&RequestId, &ReqContext)> {
+        self.reqs.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ReqContext> {
+        self.reqs.values()
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&RequestId, &ReqContext) -> bool) {
+        let removed: Vec<RequestId> = self
+            .reqs
+            .iter()
+            .filter(|(id, req_context)| !f(id, req_context))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &removed {
+            self.remove(id);
+        }
+    }
+}
 
 pub struct ReqContextAlloc {
     pub req_method: String,
@@ -65,51 +200,212 @@ impl ReqContextAlloc {
     }
 }
 
+/// A preprocessed file opened at the language server.
+pub struct OpenFile {
+    /// Number of opened source files that map to this preprocessed file.
+    pub count: u32,
+    /// Version of the document as seen by the language server.
+    pub version: i32,
+    pub language_id: String,
+}
+
+impl OpenFile {
+    pub fn next_version(&mut self) -> i32 {
+        self.version += 1;
+        self.version
+    }
+}
+
+/// Preprocess of the unsaved content of a source file, running on the preprocess worker.
+pub struct OverlayJob {
+    /// Number of configurations whose result is outstanding.
+    pub remaining: usize,
+    /// Files generated by the configurations that are done.
+    pub generated: Vec<(PathBuf, String)>,
+    /// Content of the opened source files that is preprocessed.
+    pub sources: HashMap<PathBuf, String>,
+    /// Changes to the source file since, to be applied on top of the generated files.
+    pub changes: Vec<DidChangeTextDocumentParams>,
+    /// The result is dropped, e.g. because the source file was saved or closed meanwhile.
+    pub discard: bool,
+}
+
 pub struct GlobalState {
     pub client: Connection,
-    pub server: LanguageServerTransport,
     logger: Logger,
-    pub source_mapping: FiascoSourceMapping,
-    pub open_files: HashMap<PathBuf, u32>,
+    /// Params of the client's `initialize` request, to initialize restarted language servers.
+    pub initialize_params: serde_json::Value,
+    /// Settings given on the command line, the fallback for the settings of the client.
+    pub default_settings: Settings,
+    /// Settings in effect.
+    pub settings: Settings,
+    pub configs: Vec<Configuration>,
+    pub source_mapping: SourceMappings,
+    pub open_files: HashMap<PathBuf, OpenFile>,
+    /// Source files with unsaved changes that invalidate the line mapping.
+    pub dirty_files: HashSet<PathBuf>,
+    /// Content of the opened source files, including unsaved changes.
+    pub source_overlay: HashMap<PathBuf, String>,
+    /// Content of preprocessed files generated from unsaved source files.
+    pub preprocessed_overlay: HashMap<PathBuf, String>,
+    /// Source files whose unsaved content is to be preprocessed, with the time when to do so.
+    pub pending_overlays: HashMap<PathBuf, Instant>,
+    /// Source files whose unsaved content is being preprocessed.
+    pub overlay_jobs: HashMap<PathBuf, OverlayJob>,
+    /// Runs preprocess off the main loop, its results are handled by `handle_preprocess_event`.
+    pub preprocess_worker: Worker<PreprocessJob, PreprocessEvent>,
     pub client_reqs: RequestRegistry,
     pub server_reqs: RequestRegistry,
+    /// Client requests that were split into many requests, by their id.
+    pub aggregations: HashMap<RequestId, Aggregation>,
+    /// Last diagnostics the language server of a configuration published for a preprocessed
+    /// file, mapped to the source files, see `handle_publish_diagnostics`.
+    pub diagnostics: BTreeMap<(usize, PathBuf), HashMap<String, Vec<Diagnostic>>>,
     pub next_req_id: u32,
 }
 
 impl GlobalState {
     pub fn new(
         client: Connection,
-        server: LanguageServerTransport,
         logger: Logger,
-        source_mapping: FiascoSourceMapping,
+        initialize_params: serde_json::Value,
+        default_settings: Settings,
+        settings: Settings,
+        configs: Vec<Configuration>,
+        source_mapping: SourceMappings,
     ) -> GlobalState {
         GlobalState {
             client,
-            server,
             logger,
+            initialize_params,
+            default_settings,
+            settings,
+            configs,
             source_mapping,
             open_files: HashMap::new(),
-            client_reqs: RequestRegistry::new(),
-            server_reqs: RequestRegistry::new(),
+            dirty_files: HashSet::new(),
+            source_overlay: HashMap::new(),
+            preprocessed_overlay: HashMap::new(),
+            pending_overlays: HashMap::new(),
+            overlay_jobs: HashMap::new(),
+            preprocess_worker: preprocess_worker::spawn(),
+            client_reqs: RequestRegistry::default(),
+            server_reqs: RequestRegistry::default(),
+            aggregations: HashMap::new(),
+            diagnostics: BTreeMap::new(),
             next_req_id: 0,
         }
     }
 
+    /// State serving the given build directories, with `cat` standing in for their language
+    /// servers. Also returns the client side of the connection.
+    #[cfg(test)]
+    pub fn for_test(build_dirs: &[&Path]) -> (GlobalState, Connection) {
+        use crate::build_env::BuildEnv;
+        use crate::language_server_transport;
+        use crate::source_mapping::load_source_mapping;
+
+        let (client, client_side) = Connection::memory();
+        let mut configs = Vec::new();
+        let mut source_mapping = SourceMappings::new();
+        for build_dir in build_dirs {
+            source_mapping.push(build_dir, load_source_mapping(build_dir).0);
+            let server = language_server_transport::start("cat", &[], &[]).unwrap();
+            configs.push(Configuration::new(BuildEnv::from_dir(build_dir), server));
+        }
+        let state = GlobalState::new(
+            client,
+            Logger::spawn(0),
+            serde_json::Value::Null,
+            Settings::default(),
+            Settings::default(),
+            configs,
+            source_mapping,
+        );
+        (state, client_side)
+    }
+
+    /// Replaces the websocket logger by one listening on another port.
+    pub fn restart_logger(&mut self, port: u16) {
+        self.logger = Logger::spawn(port);
+    }
+
     pub fn log_from_server(&mut self, msg: &lsp_server::Message) -> Result<()> {
         self.logger.send(Direction::FromServer, msg)?;
         Ok(())
     }
 
+    /// Returns the configuration whose language server is responsible for a message, i.e. the
+    /// configuration of the preprocessed file in its `textDocument` parameter.
+    pub fn route(&self, params: &serde_json::Value) -> Option<usize> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let uri = Url::parse(uri).ok().filter(|uri| uri.scheme() == "file")?;
+        self.source_mapping.config_of(Path::new(uri.path()))
+    }
+
+    /// Returns the name of the configuration the preprocessed file belongs to, if more than one
+    /// configuration is served. Used to tag results that differ between configurations.
+    pub fn config_tag(&self, path: &Path) -> Option<&str> {
+        if self.configs.len() < 2 {
+            return None;
+        }
+        self.source_mapping.config_of(path).map(|config| self.configs[config].name.as_str())
+    }
+
+    /// Sends a message to the language server of its configuration, see `route`. Requests that
+    /// do not refer to a preprocessed file go to the primary configuration, such notifications
+    /// to all configurations.
     pub fn send_to_server<M>(&mut self, m: M) -> Result<()>
     where
         M: Into<lsp_server::Message>,
     {
         let msg = m.into();
+        let route = match &msg {
+            Message::Request(req) => Some(self.route(&req.params).unwrap_or(PRIMARY)),
+            Message::Notification(not) => self.route(&not.params),
+            Message::Response(_) => {
+                panic!("Response to the language server without configuration.")
+            }
+        };
+        match route {
+            Some(config) => self.send_to_config(config, msg),
+            None => {
+                for config in 0..self.configs.len() {
+                    self.send_to_config(config, msg.clone())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a message to the language server of the given configuration. Messages to a
+    /// language server that is not running are dropped, requests fail.
+    pub fn send_to_config<M>(&mut self, config: usize, m: M) -> Result<()>
+    where
+        M: Into<lsp_server::Message>,
+    {
+        let msg = m.into();
+        if !self.configs[config].running {
+            self.drop_message(config, msg);
+            return Ok(());
+        }
         self.logger.send(Direction::ToServer, &msg)?;
-        self.server.to_lang_server.sender().send(msg)?;
+        // The server might have exited, it gets restarted once its exit is noticed.
+        if let Err(SendError(msg)) = self.configs[config].server.to_lang_server.sender().send(msg) {
+            self.drop_message(config, msg);
+        }
         Ok(())
     }
 
+    fn drop_message(&mut self, config: usize, msg: Message) {
+        let name = &self.configs[config].name;
+        warn!("Language server of {} is not running, drop message: {:?}", name, msg);
+        if let Message::Request(_) = msg {
+            let message = format!("Language server of {name} is not running.");
+            self.fail_requests(config, &message);
+        }
+    }
+
     pub fn send_to_client<M>(&mut self, m: M) -> Result<()>
     where
         M: Into<lsp_server::Message>,
@@ -118,6 +414,12 @@ impl GlobalState {
         Ok(())
     }
 
+    /// Shows a message to the user via `window/showMessage`.
+    pub fn show_message(&mut self, typ: MessageType, message: String) {
+        self.send_to_client(build_notif::<ShowMessage>(ShowMessageParams { typ, message }))
+            .expect("Lost connection to client.");
+    }
+
     pub fn send<M>(&mut self, direction: Direction, m: M) -> Result<()>
     where
         M: Into<lsp_server::Message>,
@@ -128,6 +430,18 @@ impl GlobalState {
         }
     }
 
+    /// Like `send`, but sends messages to the server to the language server of the given
+    /// configuration.
+    pub fn send_to<M>(&mut self, direction: Direction, config: usize, m: M) -> Result<()>
+    where
+        M: Into<lsp_server::Message>,
+    {
+        match direction {
+            Direction::ToServer => self.send_to_config(config, m),
+            Direction::FromServer => self.send_to_client(m),
+        }
+    }
+
     pub fn reqs(&mut self, direction: Direction) -> &mut RequestRegistry {
         match direction {
             Direction::ToServer => &mut self.client_reqs,
@@ -135,9 +449,93 @@ impl GlobalState {
         }
     }
 
+    /// Answers the client requests pending at the language server of a configuration with an
+    /// error, e.g. after the server was replaced, and drops its requests to the client. Client
+    /// requests that were split into many are answered once all their responses are in.
+    pub fn fail_requests(&mut self, config: usize, message: &str) {
+        let mut failed = Vec::new();
+        // Cancelled requests were answered already.
+        self.client_reqs.retain(|_, req_context| {
+            if req_context.config != config {
+                return true;
+            }
+            if !req_context.cancelled() {
+                failed.push(req_context.req_id.clone());
+            }
+            false
+        });
+        self.server_reqs.retain(|_, req_context| req_context.config != config);
+        for req_id in failed {
+            let error = ResponseError {
+                code: ErrorCode::RequestFailed as i32,
+                message: message.to_owned(),
+                data: None,
+            };
+            match self.aggregations.get_mut(&req_id) {
+                Some(aggregation) => {
+                    aggregation.add_error(error);
+                    self.answer_aggregation(&req_id);
+                }
+                None => {
+                    let response = Response { id: req_id, result: None, error: Some(error) };
+                    self.send_to_client(response).expect("Lost connection to client.");
+                }
+            }
+        }
+    }
+
+    /// Answers the client request with the given id if all responses to the requests it was
+    /// split into are in.
+    pub fn answer_aggregation(&mut self, req_id: &RequestId) {
+        if self.aggregations.get(req_id).is_some_and(Aggregation::is_complete) {
+            let response = self.aggregations.remove(req_id).unwrap().into_response(req_id.clone());
+            self.send_to_client(response).expect("Lost connection to client.");
+        }
+    }
+
     pub fn alloc_req_id(&mut self) -> u32 {
         let req_id = self.next_req_id;
         self.next_req_id += 1;
         req_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_registry() {
+        let mut reqs = RequestRegistry::default();
+        let client_id = RequestId::from(7);
+        for (id, config) in [(0, 0), (1, 1)] {
+            let mut req_context =
+                ReqContext::new("textDocument/hover".to_owned(), client_id.clone());
+            req_context.set_config(config);
+            reqs.insert(RequestId::from(id), req_context);
+        }
+        assert_eq!(reqs.sent_ids(&client_id), [RequestId::from(0), RequestId::from(1)]);
+
+        assert_eq!(reqs.remove(&RequestId::from(0)).unwrap().req_id(), &client_id);
+        assert_eq!(reqs.sent_ids(&client_id), [RequestId::from(1)]);
+        reqs.retain(|_, req_context| req_context.config() != 1);
+        assert!(reqs.sent_ids(&client_id).is_empty());
+        assert_eq!(reqs.values().count(), 0);
+    }
+
+    #[test]
+    fn request_timeouts() {
+        let mut req_context = ReqContext::new("textDocument/hover".to_owned(), RequestId::from(1));
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        assert!(!req_context.expired_at(now + REQUEST_TIMEOUT - second));
+        assert!(req_context.expired_at(now + REQUEST_TIMEOUT + second));
+        assert!(!req_context.forgotten_at(now + REQUEST_TIMEOUT + second));
+
+        // A cancelled request does not expire, it is forgotten after a while.
+        req_context.cancel();
+        assert!(!req_context.expired_at(now + REQUEST_TIMEOUT + second));
+        assert!(!req_context.forgotten_at(now + CANCELLED_TIMEOUT - second));
+        assert!(req_context.forgotten_at(now + CANCELLED_TIMEOUT + second));
+    }
+}
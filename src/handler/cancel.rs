@@ -0,0 +1,99 @@
+use lsp_server::{ErrorCode, RequestId, Response};
+use lsp_types::notification::Cancel;
+use lsp_types::{CancelParams, NumberOrString};
+
+use crate::configuration::PRIMARY;
+use crate::global_state::{Direction, GlobalState};
+use crate::util::build_notif;
+
+/// Handles `$/cancelRequest` from the client or a language server. The cancel is sent on with
+/// the ids the request was sent with, i.e. once for each request it was split into, and the
+/// cancelled request is answered right away. The responses to the cancelled requests are
+/// dropped, so the request is answered only once.
+pub fn handle_cancel(
+    state: &mut GlobalState,
+    direction: Direction,
+    config: usize,
+    params: CancelParams,
+) {
+    let req_id = match params.id {
+        NumberOrString::Number(id) => RequestId::from(id),
+        NumberOrString::String(id) => RequestId::from(id),
+    };
+    if !cancel_sent_requests(state, direction, config, &req_id) {
+        debug!("Cancelled request {} from {} is not pending.", req_id, direction);
+        return;
+    }
+    if let Direction::ToServer = direction {
+        state.aggregations.remove(&req_id);
+    }
+
+    let res = Response::new_err(
+        req_id,
+        ErrorCode::RequestCanceled as i32,
+        "Request was cancelled.".to_owned(),
+    );
+    state
+        .send_to(direction.reverse(), config, res)
+        .unwrap_or_else(|_| panic!("Lost connection to {}.", direction.reverse()));
+}
+
+/// Answers the client requests that were split into many, and of which some results are in
+/// while the other responses take too long. The missing requests are cancelled.
+pub fn handle_overdue_aggregations(state: &mut GlobalState) {
+    let overdue: Vec<RequestId> = state
+        .aggregations
+        .iter()
+        .filter(|(_, aggregation)| aggregation.is_overdue())
+        .map(|(req_id, _)| req_id.clone())
+        .collect();
+    for req_id in overdue {
+        warn!("Answer request {} without the responses that are still missing.", req_id);
+        cancel_sent_requests(state, Direction::ToServer, PRIMARY, &req_id);
+        let res = state.aggregations.remove(&req_id).unwrap().into_response(req_id);
+        state.send_to_client(res).expect("Lost connection to client.");
+    }
+}
+
+/// Cancels the pending requests the request with the given original id was sent as, returns
+/// whether there were any.
+fn cancel_sent_requests(
+    state: &mut GlobalState,
+    direction: Direction,
+    config: usize,
+    req_id: &RequestId,
+) -> bool {
+    let sent_ids: Vec<RequestId> = state
+        .reqs(direction)
+        .sent_ids(req_id)
+        .into_iter()
+        .filter(|id| {
+            let req_context = state.reqs(direction).get_mut(id).unwrap();
+            // Requests from different language servers can have the same id.
+            !req_context.cancelled()
+                && (matches!(direction, Direction::ToServer) || req_context.config() == config)
+        })
+        .collect();
+    if sent_ids.is_empty() {
+        return false;
+    }
+
+    for id in sent_ids {
+        cancel_sent_request(state, direction, &id);
+    }
+    true
+}
+
+/// Marks the pending request sent with the given id as cancelled, so that its response is
+/// dropped, and sends `$/cancelRequest` to its receiver.
+pub fn cancel_sent_request(state: &mut GlobalState, direction: Direction, id: &RequestId) {
+    let Some(req_context) = state.reqs(direction).get_mut(id) else {
+        return;
+    };
+    req_context.cancel();
+    let target = req_context.config();
+    let id = serde_json::from_value(serde_json::to_value(id).unwrap()).unwrap();
+    state
+        .send_to(direction, target, build_notif::<Cancel>(CancelParams { id }))
+        .unwrap_or_else(|_| panic!("Lost connection to {}.", direction));
+}
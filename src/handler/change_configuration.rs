@@ -0,0 +1,88 @@
+use color_eyre::eyre::Result;
+use lsp_types::{DidChangeConfigurationParams, MessageType};
+
+use crate::configuration::PRIMARY;
+use crate::global_state::GlobalState;
+use crate::handler::document_sync;
+use crate::settings::{Settings, SECTION};
+
+/// Applies the `fiasco` section of the changed settings, the remaining settings are forwarded to
+/// the language servers.
+pub fn handle_did_change_configuration(
+    state: &mut GlobalState,
+    mut params: DidChangeConfigurationParams,
+) -> DidChangeConfigurationParams {
+    let settings = match Settings::from_section(Some(&params.settings)) {
+        Ok(Some(settings)) => settings.or(&state.default_settings),
+        Ok(None) => return params,
+        Err(err) => {
+            state.show_message(MessageType::ERROR, format!("{err:#}"));
+            return params;
+        }
+    };
+    if let Some(settings) = params.settings.as_object_mut() {
+        settings.remove(SECTION);
+    }
+
+    if let Err(err) = apply_settings(state, settings) {
+        state.show_message(MessageType::ERROR, format!("Failed to apply settings: {err:#}"));
+    }
+    params
+}
+
+/// Puts the settings into effect, restarting what is affected by the changes. The previous
+/// settings stay in effect if that fails, so that the same settings can be applied again.
+fn apply_settings(state: &mut GlobalState, settings: Settings) -> Result<()> {
+    // Starting language servers uses the new settings.
+    let old = std::mem::replace(&mut state.settings, settings.clone());
+    debug!("Apply settings: {:?}", settings);
+
+    if let Err(err) = restart_changed(state, &settings, &old) {
+        state.settings = old;
+        return Err(err);
+    }
+    if settings.logger_port() != old.logger_port() {
+        state.restart_logger(settings.logger_port());
+    }
+    Ok(())
+}
+
+/// Replaces the primary configuration and restarts the language servers, as far as the
+/// settings changed.
+fn restart_changed(state: &mut GlobalState, settings: &Settings, old: &Settings) -> Result<()> {
+    let mut replaced = false;
+    if settings.build_changed(old) {
+        if let Some(build_env) = settings.build_env()? {
+            document_sync::replace_configuration(state, PRIMARY, build_env)?;
+            replaced = true;
+            let message = format!("Switched to configuration {}.", state.configs[PRIMARY].name);
+            state.show_message(MessageType::INFO, message);
+        }
+    }
+
+    if settings.server_changed(old) {
+        let configs = (0..state.configs.len()).filter(|&config| !(replaced && config == PRIMARY));
+        for config in configs.collect::<Vec<_>>() {
+            document_sync::restart_server(state, config, "The clangd settings changed.")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_settings_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut state, _client) = GlobalState::for_test(&[dir.path()]);
+        let settings = Settings {
+            build_dir: Some(dir.path().join("missing")),
+            logger_port: Some(1),
+            ..Default::default()
+        };
+        assert!(apply_settings(&mut state, settings).is_err());
+        assert_eq!(state.settings, Settings::default());
+    }
+}
@@ -0,0 +1,261 @@
+use std::path::Path;
+
+use lsp_types::{CompletionItem, CompletionResponse, CompletionTextEdit, Range, TextEdit};
+use serde_json::{json, Value};
+
+use crate::global_state::{GlobalState, ReqContext};
+use crate::source_mapping::MapDirection::FromPreprocess;
+
+/// Key of the `data` of completion items under which the proxy keeps the source file and the
+/// preprocessed file of the completion, for `completionItem/resolve`.
+const DATA_KEY: &str = "fiasco";
+
+/// Maps the edits of the completion items back to the source file. Items whose text edit cannot
+/// be mapped are dropped, additional text edits that cannot be mapped are removed.
+pub fn handle_res_completion(
+    state: &mut GlobalState,
+    req_context: &mut ReqContext,
+    res: Option<CompletionResponse>,
+) -> Option<CompletionResponse> {
+    let (source_path, mapped_path) = match req_context.take_value::<(String, String)>() {
+        None => return res,
+        Some(t) => t,
+    };
+    let mut result = res?;
+    let items = match &mut result {
+        CompletionResponse::Array(items) => items,
+        CompletionResponse::List(list) => &mut list.items,
+    };
+    items.retain_mut(|item| {
+        if !map_item(state, &source_path, &mapped_path, item) {
+            warn!("Completion: Drop {} with unmappable text edit.", item.label);
+            return false;
+        }
+        let data = item.data.take().unwrap_or(Value::Null);
+        item.data.replace(json!({
+            DATA_KEY: { "sourcePath": &source_path, "mappedPath": &mapped_path },
+            "data": data,
+        }));
+        true
+    });
+    Some(result)
+}
+
+/// Restores the `data` of the item given by the language server, and keeps the files of the
+/// completion and the edits of the item, which are mapped already, for the response. The item
+/// is resolved by the language server of the preprocessed file of the completion.
+pub fn handle_req_resolve_completion(
+    state: &mut GlobalState,
+    req_context: &mut ReqContext,
+    mut item: CompletionItem,
+) -> CompletionItem {
+    let files = item.data.as_ref().and_then(|data| {
+        let files = data.get(DATA_KEY)?;
+        let source_path = files.get("sourcePath")?.as_str()?.to_owned();
+        let mapped_path = files.get("mappedPath")?.as_str()?.to_owned();
+        Some((source_path, mapped_path))
+    });
+    let Some((source_path, mapped_path)) = files else {
+        return item;
+    };
+    item.data = item
+        .data
+        .take()
+        .and_then(|mut data| data.get_mut("data").map(Value::take))
+        .filter(|data| !data.is_null());
+
+    if let Some(config) = state.source_mapping.config_of(Path::new(&mapped_path)) {
+        req_context.set_config(config);
+    }
+    let edits = (item.text_edit.clone(), item.additional_text_edits.clone().unwrap_or_default());
+    req_context.set_value((source_path, mapped_path, edits));
+    item
+}
+
+/// Maps the edits the language server added when resolving the item back to the source file,
+/// like `handle_res_completion`. The edits of the request are kept as they are.
+pub fn handle_res_resolve_completion(
+    state: &mut GlobalState,
+    req_context: &mut ReqContext,
+    mut item: CompletionItem,
+) -> CompletionItem {
+    type Edits = (Option<CompletionTextEdit>, Vec<TextEdit>);
+    let Some((source_path, mapped_path, (text_edit, additional_text_edits))) =
+        req_context.take_value::<(String, String, Edits)>()
+    else {
+        return item;
+    };
+
+    if item.text_edit.is_some() && item.text_edit != text_edit {
+        let mapped = item
+            .text_edit
+            .as_mut()
+            .is_some_and(|edit| map_text_edit(state, &source_path, &mapped_path, edit));
+        if !mapped {
+            warn!("Completion: Keep text edit of {}, the resolved one is unmappable.", item.label);
+            item.text_edit = text_edit;
+        }
+    }
+    if let Some(edits) = item.additional_text_edits.as_mut() {
+        edits.retain_mut(|edit| {
+            additional_text_edits.contains(edit)
+                || map_additional_text_edit(state, &source_path, &mapped_path, edit)
+        });
+    }
+    item
+}
+
+/// Maps the text edit and the additional text edits of an item from the preprocessed file to
+/// the source file. Returns `false` if the text edit cannot be mapped, additional text edits that
+/// cannot be mapped are removed.
+fn map_item(
+    state: &GlobalState,
+    source_path: &str,
+    mapped_path: &str,
+    item: &mut CompletionItem,
+) -> bool {
+    if let Some(edits) = item.additional_text_edits.as_mut() {
+        edits.retain_mut(|edit| map_additional_text_edit(state, source_path, mapped_path, edit));
+    }
+    match item.text_edit.as_mut() {
+        None => true,
+        Some(edit) => map_text_edit(state, source_path, mapped_path, edit),
+    }
+}
+
+fn map_text_edit(
+    state: &GlobalState,
+    source_path: &str,
+    mapped_path: &str,
+    edit: &mut CompletionTextEdit,
+) -> bool {
+    match edit {
+        CompletionTextEdit::Edit(edit) => {
+            map_range(state, source_path, mapped_path, &mut edit.range)
+        }
+        CompletionTextEdit::InsertAndReplace(edit) => {
+            map_range(state, source_path, mapped_path, &mut edit.insert)
+                && map_range(state, source_path, mapped_path, &mut edit.replace)
+        }
+    }
+}
+
+fn map_additional_text_edit(
+    state: &GlobalState,
+    source_path: &str,
+    mapped_path: &str,
+    edit: &mut TextEdit,
+) -> bool {
+    let mapped = map_range(state, source_path, mapped_path, &mut edit.range);
+    if !mapped {
+        warn!("Completion: Drop unmappable additional text edit {:?}.", edit);
+    }
+    mapped
+}
+
+/// Maps a range of the preprocessed file to the source file, returns `false` if it maps to
+/// another file.
+fn map_range(state: &GlobalState, source_path: &str, mapped_path: &str, range: &mut Range) -> bool {
+    let mut path = mapped_path.to_owned();
+    state.source_mapping.map_range(FromPreprocess, &mut path, range).is_ok() && path == source_path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lsp_types::{InsertReplaceEdit, Position};
+
+    use super::*;
+
+    fn range(line: u32) -> Range {
+        Range::new(Position::new(line, 0), Position::new(line, 1))
+    }
+
+    fn edit(line: u32) -> TextEdit {
+        TextEdit::new(range(line), "x".to_owned())
+    }
+
+    #[test]
+    fn map_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        let other_source = dir.path().join("bar.cpp");
+        let mut build_dirs = Vec::new();
+        for name in ["arm64", "amd64"] {
+            let build_dir = dir.path().join(name);
+            fs::create_dir_all(build_dir.join("auto")).unwrap();
+            fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+            let line = |path: &Path| format!("#line 1 \"{}\"\n", path.display());
+            let text = format!("{}a;\nb;\n{}c;\n", line(&source), line(&other_source));
+            fs::write(build_dir.join("auto/foo.cpp"), text).unwrap();
+            build_dirs.push(build_dir);
+        }
+        let (mut state, _client) =
+            GlobalState::for_test(&[build_dirs[0].as_path(), build_dirs[1].as_path()]);
+        let source_path = source.to_str().unwrap().to_owned();
+        let mapped_path = build_dirs[1].join("auto/foo.cpp").to_str().unwrap().to_owned();
+
+        let items = vec![
+            CompletionItem {
+                label: "edit".to_owned(),
+                text_edit: Some(CompletionTextEdit::Edit(edit(2))),
+                // The edit in the lines of bar.cpp is removed.
+                additional_text_edits: Some(vec![edit(4), edit(1)]),
+                data: Some(json!({ "id": 1 })),
+                ..Default::default()
+            },
+            CompletionItem {
+                label: "insert_replace".to_owned(),
+                text_edit: Some(CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                    new_text: "x".to_owned(),
+                    insert: range(1),
+                    replace: range(2),
+                })),
+                ..Default::default()
+            },
+            CompletionItem {
+                label: "unmappable".to_owned(),
+                text_edit: Some(CompletionTextEdit::Edit(edit(4))),
+                ..Default::default()
+            },
+        ];
+        let mut req_context = ReqContext::new("textDocument/completion".to_owned(), 1.into());
+        req_context.set_value((source_path.clone(), mapped_path.clone()));
+        let res = Some(CompletionResponse::Array(items));
+        let Some(CompletionResponse::Array(items)) =
+            handle_res_completion(&mut state, &mut req_context, res)
+        else {
+            panic!("Unexpected completion response.");
+        };
+        let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, ["edit", "insert_replace"]);
+        assert_eq!(items[0].text_edit, Some(CompletionTextEdit::Edit(edit(1))));
+        assert_eq!(items[0].additional_text_edits, Some(vec![edit(0)]));
+        let Some(CompletionTextEdit::InsertAndReplace(insert_replace)) = &items[1].text_edit else {
+            panic!("Unexpected text edit {:?}.", items[1].text_edit);
+        };
+        assert_eq!((insert_replace.insert, insert_replace.replace), (range(0), range(1)));
+        assert_eq!(items[1].data.as_ref().unwrap()["data"], Value::Null);
+
+        // Resolving restores the data and goes to the configuration of the completion.
+        let mut req_context = ReqContext::new("completionItem/resolve".to_owned(), 2.into());
+        let item = handle_req_resolve_completion(&mut state, &mut req_context, items[0].clone());
+        assert_eq!(item.data, Some(json!({ "id": 1 })));
+        assert_eq!(req_context.config(), 1);
+
+        // Only the edit the language server added is mapped.
+        let mut resolved = item.clone();
+        resolved.additional_text_edits.as_mut().unwrap().push(edit(2));
+        let resolved = handle_res_resolve_completion(&mut state, &mut req_context, resolved);
+        assert_eq!(resolved.text_edit, item.text_edit);
+        assert_eq!(resolved.additional_text_edits, Some(vec![edit(0), edit(1)]));
+        assert_eq!(resolved.data, Some(json!({ "id": 1 })));
+
+        // Items of other language servers are resolved as they are.
+        let mut req_context = ReqContext::new("completionItem/resolve".to_owned(), 3.into());
+        let item = CompletionItem { label: "plain".to_owned(), ..Default::default() };
+        let item = handle_req_resolve_completion(&mut state, &mut req_context, item);
+        assert_eq!((item.data, req_context.config()), (None, 0));
+    }
+}
@@ -1,10 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 
+use lsp_types::notification::PublishDiagnostics;
 use lsp_types::{PublishDiagnosticsParams, Url};
 
+use crate::configuration::PRIMARY;
 use crate::global_state::GlobalState;
 use crate::source_mapping::MapDirection::FromPreprocess;
+use crate::util::build_notif;
 
+/// Maps the diagnostics of a preprocessed file to the source files, and publishes the
+/// diagnostics of each affected source file merged with those of its other preprocessed files
+/// and configurations, see `GlobalState::diagnostics`.
 pub fn handle_publish_diagnostics(
     state: &mut GlobalState,
     params: PublishDiagnosticsParams,
@@ -20,12 +27,20 @@ pub fn handle_publish_diagnostics(
         return vec![params];
     }
 
+    let tag = state.config_tag(Path::new(params.uri.path())).map(str::to_owned);
     let mut result = HashMap::new();
     for mut diagnostic in params.diagnostics {
+        if let Some(tag) = &tag {
+            // Tell apart the diagnostics of the configurations.
+            diagnostic.source = Some(match diagnostic.source {
+                Some(source) => format!("{source} ({tag})"),
+                None => tag.clone(),
+            });
+        }
         let mut path = params.uri.path().to_owned();
         if diagnostic.range.start == diagnostic.range.end {
             // Diagnostic for the entire file
-            for file in files {
+            for file in &files {
                 result
                     .entry(file.to_str().unwrap().to_owned())
                     .or_insert(Vec::new())
@@ -40,13 +55,157 @@ pub fn handle_publish_diagnostics(
         }
     }
 
-    result
+    // Publish the source files that had diagnostics of the preprocessed file before as well, to
+    // remove those.
+    let path = PathBuf::from(params.uri.path());
+    let key = (state.source_mapping.config_of(&path).unwrap_or(PRIMARY), path);
+    let mut sources: BTreeSet<String> = result.keys().cloned().collect();
+    let old = match result.is_empty() {
+        true => state.diagnostics.remove(&key),
+        false => state.diagnostics.insert(key, result),
+    };
+    sources.extend(old.into_iter().flat_map(HashMap::into_keys));
+    merged_diagnostics(state, sources)
+}
+
+/// Drops the diagnostics of a configuration, e.g. when it is replaced, and publishes the
+/// remaining diagnostics of the affected source files.
+pub fn clear_diagnostics(state: &mut GlobalState, config: usize) {
+    let mut sources = BTreeSet::new();
+    state.diagnostics.retain(|(c, _), diagnostics| {
+        if *c == config {
+            sources.extend(diagnostics.keys().cloned());
+        }
+        *c != config
+    });
+    for params in merged_diagnostics(state, sources) {
+        state
+            .send_to_client(build_notif::<PublishDiagnostics>(params))
+            .expect("Lost connection to client.");
+    }
+}
+
+/// Builds the notifications publishing the diagnostics of the given source files, merged from
+/// all preprocessed files and configurations.
+pub fn merged_diagnostics(
+    state: &GlobalState,
+    sources: impl IntoIterator<Item = String>,
+) -> Vec<PublishDiagnosticsParams> {
+    sources
         .into_iter()
-        .map(|(file, diagnostics)| PublishDiagnosticsParams {
-            uri: Url::from_file_path(file).unwrap(),
-            diagnostics,
-            // TODO: We need custom version numbering...
-            version: params.version,
+        .map(|source| PublishDiagnosticsParams {
+            uri: Url::from_file_path(&source).unwrap(),
+            diagnostics: state
+                .diagnostics
+                .values()
+                .filter_map(|diagnostics| diagnostics.get(&source))
+                .flatten()
+                .cloned()
+                .collect(),
+            // The versions of the preprocessed files are not the one of the source file.
+            version: None,
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lsp_types::{Diagnostic, Position, Range};
+
+    use super::*;
+
+    fn publish(path: &Path, lines: &[u32]) -> PublishDiagnosticsParams {
+        let diagnostics = lines
+            .iter()
+            .map(|&line| {
+                let range = Range::new(Position::new(line, 0), Position::new(line, 1));
+                Diagnostic::new_simple(range, "error".to_owned())
+            })
+            .collect();
+        PublishDiagnosticsParams::new(Url::from_file_path(path).unwrap(), diagnostics, None)
+    }
+
+    #[test]
+    fn merge_diagnostics() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        let mut build_dirs = Vec::new();
+        for name in ["arm64", "amd64"] {
+            let build_dir = dir.path().join(name);
+            fs::create_dir_all(build_dir.join("auto")).unwrap();
+            fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+            let line = |n| format!("#line {n} \"{}\"\n", source.display());
+            fs::write(build_dir.join("auto/foo.cpp"), format!("{}a;\nb;\n", line(1))).unwrap();
+            fs::write(build_dir.join("auto/foo_i.h"), format!("{}c;\n", line(5))).unwrap();
+            build_dirs.push(build_dir);
+        }
+        let (mut state, _client) =
+            GlobalState::for_test(&[build_dirs[0].as_path(), build_dirs[1].as_path()]);
+        let arm64 = build_dirs[0].join("auto/foo.cpp");
+        let arm64_header = build_dirs[0].join("auto/foo_i.h");
+        let amd64 = build_dirs[1].join("auto/foo.cpp");
+        // The source lines of the published diagnostics.
+        let lines = |result: &[PublishDiagnosticsParams]| -> Vec<Vec<u32>> {
+            result
+                .iter()
+                .inspect(|params| assert_eq!(params.uri.path(), source.to_str().unwrap()))
+                .map(|params| params.diagnostics.iter().map(|d| d.range.start.line).collect())
+                .collect()
+        };
+
+        let result = handle_publish_diagnostics(&mut state, publish(&arm64, &[1, 2]));
+        assert_eq!(lines(&result), [[0, 1]]);
+        // Sibling files and configurations do not overwrite each other.
+        let result = handle_publish_diagnostics(&mut state, publish(&arm64_header, &[1]));
+        assert_eq!(lines(&result), [[0, 1, 4]]);
+        let result = handle_publish_diagnostics(&mut state, publish(&amd64, &[2]));
+        assert_eq!(lines(&result), [[0, 1, 4, 1]]);
+
+        // Empty publishes remove the diagnostics of their file only, until none are left.
+        let result = handle_publish_diagnostics(&mut state, publish(&arm64, &[]));
+        assert_eq!(lines(&result), [[4, 1]]);
+        handle_publish_diagnostics(&mut state, publish(&arm64_header, &[]));
+        let result = handle_publish_diagnostics(&mut state, publish(&amd64, &[]));
+        assert_eq!(lines(&result), [Vec::<u32>::new()]);
+        assert!(state.diagnostics.is_empty());
+        // Nothing to remove.
+        assert!(handle_publish_diagnostics(&mut state, publish(&amd64, &[])).is_empty());
+    }
+
+    #[test]
+    fn clear_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        let mut build_dirs = Vec::new();
+        for name in ["arm64", "amd64"] {
+            let build_dir = dir.path().join(name);
+            fs::create_dir_all(build_dir.join("auto")).unwrap();
+            fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+            let text = format!("#line 1 \"{}\"\na;\n", source.display());
+            fs::write(build_dir.join("auto/foo.cpp"), text).unwrap();
+            build_dirs.push(build_dir);
+        }
+        let (mut state, client) =
+            GlobalState::for_test(&[build_dirs[0].as_path(), build_dirs[1].as_path()]);
+        handle_publish_diagnostics(&mut state, publish(&build_dirs[0].join("auto/foo.cpp"), &[1]));
+        handle_publish_diagnostics(&mut state, publish(&build_dirs[1].join("auto/foo.cpp"), &[1]));
+
+        let published = || {
+            let msg = client.receiver.try_recv().unwrap();
+            let lsp_server::Message::Notification(not) = msg else {
+                panic!("Unexpected message {msg:?}");
+            };
+            let params: PublishDiagnosticsParams = serde_json::from_value(not.params).unwrap();
+            assert_eq!(params.uri.path(), source.to_str().unwrap());
+            params.diagnostics.into_iter().map(|d| d.source.unwrap()).collect::<Vec<_>>()
+        };
+        clear_diagnostics(&mut state, 0);
+        assert_eq!(published(), ["amd64"]);
+        clear_diagnostics(&mut state, 1);
+        assert!(published().is_empty());
+        clear_diagnostics(&mut state, 1);
+        assert!(client.receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use lsp_types::request::DocumentColor;
+use lsp_types::{ColorInformation, DocumentColorParams, TextDocumentIdentifier};
+
+use crate::global_state::GlobalState;
+use crate::handler::document_split::{DocumentSplit, SplitFile};
+
+impl DocumentSplit for DocumentColor {
+    type Item = ColorInformation;
+
+    fn text_document(params: &mut DocumentColorParams) -> &mut TextDocumentIdentifier {
+        &mut params.text_document
+    }
+
+    fn into_items(result: Vec<ColorInformation>) -> Vec<ColorInformation> {
+        result
+    }
+
+    fn from_items(items: Vec<ColorInformation>) -> Vec<ColorInformation> {
+        items
+    }
+
+    fn map_item(state: &mut GlobalState, file: &SplitFile, color: &mut ColorInformation) -> bool {
+        file.map_range(state, &mut color.range)
+    }
+}
@@ -2,6 +2,7 @@ use lsp_types::DocumentHighlight;
 
 use crate::global_state::{GlobalState, ReqContext};
 use crate::source_mapping::MapDirection::FromPreprocess;
+use crate::util::dedup;
 
 pub fn handle_res_document_highlight(
     state: &mut GlobalState,
@@ -35,3 +36,10 @@ pub fn handle_res_document_highlight(
     });
     Some(result)
 }
+
+pub fn merge_document_highlights(
+    results: Vec<Option<Vec<DocumentHighlight>>>,
+) -> Option<Vec<DocumentHighlight>> {
+    let highlights: Vec<DocumentHighlight> = results.into_iter().flatten().flatten().collect();
+    Some(dedup(highlights))
+}
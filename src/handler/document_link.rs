@@ -0,0 +1,36 @@
+use lsp_types::request::DocumentLinkRequest;
+use lsp_types::{DocumentLink, DocumentLinkParams, TextDocumentIdentifier, Url};
+
+use crate::global_state::GlobalState;
+use crate::handler::document_split::{DocumentSplit, SplitFile};
+use crate::source_mapping::MapDirection::FromPreprocess;
+
+impl DocumentSplit for DocumentLinkRequest {
+    type Item = DocumentLink;
+
+    fn text_document(params: &mut DocumentLinkParams) -> &mut TextDocumentIdentifier {
+        &mut params.text_document
+    }
+
+    fn into_items(result: Option<Vec<DocumentLink>>) -> Vec<DocumentLink> {
+        result.unwrap_or_default()
+    }
+
+    fn from_items(items: Vec<DocumentLink>) -> Option<Vec<DocumentLink>> {
+        Some(items)
+    }
+
+    fn map_item(state: &mut GlobalState, file: &SplitFile, link: &mut DocumentLink) -> bool {
+        if !file.map_range(state, &mut link.range) {
+            return false;
+        }
+        // Includes of preprocessed headers link to their source file.
+        if let Some(target) = link.target.as_mut().filter(|target| target.scheme() == "file") {
+            let sources = state.source_mapping.map_files(FromPreprocess, target.path());
+            if let Some(source) = sources.first() {
+                *target = Url::from_file_path(source).unwrap();
+            }
+        }
+        true
+    }
+}
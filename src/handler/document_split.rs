@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use lsp_types::request::Request;
+use lsp_types::{Position, Range, TextDocumentIdentifier, Url};
+
+use crate::global_state::{GlobalState, ReqContext, ReqContextAlloc};
+use crate::source_mapping::MapDirection::{FromPreprocess, ToPreprocess};
+
+/// Description of a request for a document, and optionally a range of it, whose result is a list
+/// of elements in that document. Such a request is split into one request per preprocessed file
+/// of the document, see `RequestDispatcher::on_split`. The elements of the results are mapped
+/// back to the document and merged, see `ResponseDispatcher::on_split`.
+pub trait DocumentSplit: Request {
+    type Item;
+
+    fn text_document(params: &mut Self::Params) -> &mut TextDocumentIdentifier;
+
+    /// Range of the document the request is for. Only the preprocessed files the range maps to
+    /// are asked, for all of their content, as the range maps to different lines in each file.
+    fn range(_params: &mut Self::Params) -> Option<&mut Range> {
+        None
+    }
+
+    fn into_items(result: Self::Result) -> Vec<Self::Item>;
+
+    fn from_items(items: Vec<Self::Item>) -> Self::Result;
+
+    /// Maps an element of the result from the preprocessed file back to the document, returns
+    /// `false` to drop it, e.g. if it belongs to another source file.
+    fn map_item(state: &mut GlobalState, file: &SplitFile, item: &mut Self::Item) -> bool;
+}
+
+/// The document and the preprocessed file of one of the requests a document request was split
+/// into.
+pub struct SplitFile {
+    pub source_path: String,
+    pub mapped_path: String,
+}
+
+impl SplitFile {
+    /// Maps a range of the preprocessed file back to the document, returns `false` if it maps
+    /// to another file.
+    pub fn map_range(&self, state: &GlobalState, range: &mut Range) -> bool {
+        let mut path = self.mapped_path.clone();
+        state.source_mapping.map_range(FromPreprocess, &mut path, range).is_ok()
+            && path == self.source_path
+    }
+
+    /// Maps a position of the preprocessed file back to the document, returns `false` if it maps
+    /// to another file.
+    pub fn map_position(&self, state: &GlobalState, position: &mut Position) -> bool {
+        let mut path = self.mapped_path.clone();
+        state.source_mapping.map_position(FromPreprocess, &mut path, position);
+        path == self.source_path
+    }
+}
+
+/// Splits up a document request into one request per preprocessed file of the document.
+pub fn split<R>(
+    state: &mut GlobalState,
+    req_context_alloc: &ReqContextAlloc,
+    mut params: R::Params,
+) -> Vec<(R::Params, ReqContext)>
+where
+    R: DocumentSplit,
+    R::Params: Clone,
+{
+    let doc = R::text_document(&mut params);
+    if doc.uri.scheme() != "file" {
+        info!("{}: Encountered unsupported scheme {}.", R::METHOD, doc.uri);
+        return vec![(params, req_context_alloc.alloc())];
+    }
+
+    let source_path = doc.uri.path().to_owned();
+    // Only ask one configuration, the results of the others would be duplicates.
+    let source_mapping = state.source_mapping.preferred(&source_path);
+    let files: Vec<PathBuf> = match R::range(&mut params) {
+        Some(range) => source_mapping
+            .map_files_with_range(ToPreprocess, &source_path, range.start.line, range.end.line)
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect(),
+        None => source_mapping.map_files(ToPreprocess, &source_path).to_vec(),
+    };
+    if files.is_empty() {
+        warn!("{}: Encountered unknown file or unmappable range in {}.", R::METHOD, source_path);
+        return vec![(params, req_context_alloc.alloc())];
+    }
+
+    files
+        .into_iter()
+        .map(|mapped_path| {
+            let mut req_params = params.clone();
+            R::text_document(&mut req_params).uri = Url::from_file_path(&mapped_path).unwrap();
+            if let Some(range) = R::range(&mut req_params) {
+                let end = state.source_mapping.file_length(FromPreprocess, &mapped_path).unwrap();
+                *range = Range::new(Position::new(0, 0), Position::new(end, 0));
+            }
+
+            let mut req_context = req_context_alloc.alloc();
+            req_context.set_value(SplitFile {
+                source_path: source_path.clone(),
+                mapped_path: mapped_path.to_str().unwrap().to_owned(),
+            });
+            (req_params, req_context)
+        })
+        .collect()
+}
+
+/// Maps the elements of the result of one of the requests created by `split`.
+pub fn map<R: DocumentSplit>(
+    state: &mut GlobalState,
+    req_context: &mut ReqContext,
+    res: R::Result,
+) -> R::Result {
+    let Some(file) = req_context.take_value::<SplitFile>() else {
+        return res;
+    };
+    let mut items = R::into_items(res);
+    items.retain_mut(|item| R::map_item(state, &file, item));
+    R::from_items(items)
+}
+
+/// Merges the mapped results of the requests created by `split`.
+pub fn merge<R: DocumentSplit>(results: Vec<R::Result>) -> R::Result {
+    R::from_items(results.into_iter().flat_map(R::into_items).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lsp_server::RequestId;
+    use lsp_types::request::InlayHintRequest;
+    use lsp_types::{InlayHint, InlayHintLabel, InlayHintParams};
+
+    use super::*;
+
+    fn inlay_hint(line: u32) -> InlayHint {
+        InlayHint {
+            position: Position::new(line, 0),
+            label: InlayHintLabel::String("hint".to_owned()),
+            kind: None,
+            text_edits: None,
+            tooltip: None,
+            padding_left: None,
+            padding_right: None,
+            data: None,
+        }
+    }
+
+    #[test]
+    fn split_inlay_hints() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("build");
+        let source = dir.path().join("foo.cpp");
+        let other_source = dir.path().join("bar.cpp");
+        fs::create_dir_all(build_dir.join("auto")).unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        let implementation = build_dir.join("auto/foo.cpp");
+        let header = build_dir.join("auto/foo_i.h");
+        let line = |n, path: &Path| format!("#line {n} \"{}\"\n", path.display());
+        let text = format!("{}a;\nb;\n{}c;\n", line(1, &source), line(1, &other_source));
+        fs::write(&implementation, text).unwrap();
+        fs::write(&header, format!("{}d;\n", line(10, &source))).unwrap();
+        let (mut state, _client) = GlobalState::for_test(&[build_dir.as_path()]);
+
+        let params = InlayHintParams {
+            work_done_progress_params: Default::default(),
+            text_document: TextDocumentIdentifier::new(Url::from_file_path(&source).unwrap()),
+            range: Range::new(Position::new(0, 0), Position::new(9, 0)),
+        };
+        let alloc = ReqContextAlloc {
+            req_method: InlayHintRequest::METHOD.to_owned(),
+            req_id: RequestId::from(1),
+        };
+        let mut reqs = split::<InlayHintRequest>(&mut state, &alloc, params);
+        reqs.sort_by(|(a, _), (b, _)| a.text_document.uri.path().cmp(b.text_document.uri.path()));
+        let files: Vec<_> = reqs
+            .iter()
+            .map(|(params, _)| (PathBuf::from(params.text_document.uri.path()), params.range))
+            .collect();
+        // Both preprocessed files are asked as a whole.
+        assert_eq!(
+            files,
+            [
+                (implementation, Range::new(Position::new(0, 0), Position::new(4, 0))),
+                (header, Range::new(Position::new(0, 0), Position::new(1, 0)))
+            ]
+        );
+
+        // The hint in the lines of bar.cpp is dropped.
+        let results = [vec![inlay_hint(2), inlay_hint(4)], vec![inlay_hint(1)]];
+        let results = reqs
+            .into_iter()
+            .zip(results)
+            .map(|((_, mut req_context), hints)| {
+                map::<InlayHintRequest>(&mut state, &mut req_context, Some(hints))
+            })
+            .collect();
+        let hints = merge::<InlayHintRequest>(results).unwrap();
+        let lines: Vec<u32> = hints.iter().map(|hint| hint.position.line).collect();
+        assert_eq!(lines, [1, 9]);
+    }
+}
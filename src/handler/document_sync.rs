@@ -1,12 +1,28 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use lsp_types::notification::{
+    DidChangeTextDocument, DidChangeWatchedFiles, DidCloseTextDocument, DidOpenTextDocument,
+};
 use lsp_types::{
-    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
-    TextDocumentIdentifier, TextDocumentItem, Url, VersionedTextDocumentIdentifier,
+    DidChangeTextDocumentParams, DidChangeWatchedFilesParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, FileChangeType, FileEvent, MessageType,
+    TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem, Url,
+    VersionedTextDocumentIdentifier,
 };
 
-use crate::global_state::GlobalState;
+use color_eyre::eyre::Result;
+
+use crate::build_env::BuildEnv;
+use crate::build_watcher::BuildEvent;
+use crate::configuration::{start_server, Configuration};
+use crate::global_state::{GlobalState, OpenFile, OverlayJob};
+use crate::handler::diagnostics;
+use crate::preprocess_worker::{PreprocessEvent, PreprocessJob};
+use crate::source_mapping::load_source_mapping;
 use crate::source_mapping::MapDirection::ToPreprocess;
+use crate::util::{apply_content_change, build_notif};
 
 pub fn handle_did_open_text_document(
     state: &mut GlobalState,
@@ -23,31 +39,40 @@ pub fn handle_did_open_text_document(
         warn!("DidOpenTextDocument: Encountered unknown file {}.", doc.uri.path());
         return vec![params];
     }
+    state.source_overlay.insert(PathBuf::from(doc.uri.path()), doc.text.clone());
 
     let mut result = Vec::new();
     for file in files {
-        if let Some(count) = state.open_files.get_mut(file) {
-            *count += 1;
+        if let Some(open_file) = state.open_files.get_mut(&file) {
+            open_file.count += 1;
             // File already opened, multiple source files might map to the same preprocessed file),
             // we must sent another open notification.
             continue;
         }
 
         // Remember that file is opened and send notification to server.
-        state.open_files.insert(file.clone(), 1);
+        let open_file = OpenFile { count: 1, version: 0, language_id: doc.language_id.clone() };
         result.push(DidOpenTextDocumentParams {
             text_document: TextDocumentItem {
-                uri: Url::from_file_path(file).unwrap(),
-                language_id: doc.language_id.clone(),
-                // TODO: We need custom version numbering...
-                version: doc.version,
-                text: std::fs::read_to_string(file).unwrap(),
+                uri: Url::from_file_path(&file).unwrap(),
+                language_id: open_file.language_id.clone(),
+                version: open_file.version,
+                text: state
+                    .preprocessed_overlay
+                    .get(&file)
+                    .cloned()
+                    .unwrap_or_else(|| std::fs::read_to_string(&file).unwrap()),
             },
-        })
+        });
+        state.open_files.insert(file, open_file);
     }
     result
 }
 
+/// Unsaved content is preprocessed once the source file was not changed for this long, instead
+/// of on every keystroke.
+const PREPROCESS_DELAY: Duration = Duration::from_millis(500);
+
 pub fn handle_did_change_text_document(
     state: &mut GlobalState,
     params: DidChangeTextDocumentParams,
@@ -64,28 +89,130 @@ pub fn handle_did_change_text_document(
         return vec![params];
     }
 
+    let source_path = PathBuf::from(doc.uri.path());
+    if let Some(text) = state.source_overlay.get_mut(&source_path) {
+        for change in &params.content_changes {
+            apply_content_change(text, change);
+        }
+    }
+    if let Some(job) = state.overlay_jobs.get_mut(&source_path) {
+        // The generated files do not contain the change yet, see `apply_overlay`.
+        job.changes.push(params.clone());
+    }
+
+    forward_changes(state, params)
+}
+
+/// Maps the changes of a source file to the preprocessed files it is mapped to. Changes outside
+/// of the mapped lines are picked up by preprocessing the unsaved content of the source file.
+fn forward_changes(
+    state: &mut GlobalState,
+    params: DidChangeTextDocumentParams,
+) -> Vec<DidChangeTextDocumentParams> {
+    let source_path = params.text_document.uri.path().to_owned();
+    let mut needs_preprocess = false;
     let mut result = HashMap::new();
-    for mut change in params.content_changes {
-        match &mut change.range {
-            Some(range) => {
-                let mut path = doc.uri.path().to_owned();
-                if state.source_mapping.map_range(ToPreprocess, &mut path, range).is_ok() {
+    for change in params.content_changes {
+        match change.range {
+            Some(source_range) => {
+                let added_lines = change.text.matches('\n').count() as u32;
+                // The edit goes to every preprocessed file the range is mapped to, e.g. to the
+                // files of all configurations.
+                let mapped =
+                    state.source_mapping.map_range_all(ToPreprocess, &source_path, &source_range);
+
+                if added_lines != source_range.end.line - source_range.start.line {
+                    // Keep the line mapping in sync with the edit, on both sides. This is only an
+                    // approximation until preprocess is re-run on save.
+                    state.dirty_files.insert(PathBuf::from(&source_path));
+                    state.source_mapping.shift_lines(
+                        Path::new(&source_path),
+                        source_range.start.line,
+                        source_range.end.line,
+                        source_range.start.line + added_lines,
+                    );
+                    for (path, range) in &mapped {
+                        state.source_mapping.shift_lines(
+                            Path::new(path),
+                            range.start.line,
+                            range.end.line,
+                            range.start.line + added_lines,
+                        );
+                    }
+                }
+
+                if mapped.is_empty() {
+                    // Edit outside of mapped blocks, e.g. a new method.
+                    needs_preprocess = true;
+                }
+                for (path, range) in mapped {
+                    let change =
+                        TextDocumentContentChangeEvent { range: Some(range), ..change.clone() };
                     result.entry(path).or_insert(Vec::new()).push(change);
                 }
             }
-            None => warn!("TODO: Changing of entire files not yet implemented."),
+            None => needs_preprocess = true,
         }
     }
 
+    // Forward the mappable edits until the unsaved content is preprocessed, which replaces the
+    // content of the preprocessed files, see `handle_pending_overlays`.
+    if needs_preprocess {
+        state
+            .pending_overlays
+            .insert(PathBuf::from(&source_path), Instant::now() + PREPROCESS_DELAY);
+    }
+
     result
         .into_iter()
-        .map(|(file, changes)| DidChangeTextDocumentParams {
-            // TODO: We need custom version numbering...
-            text_document: VersionedTextDocumentIdentifier::new(
-                Url::from_file_path(file).unwrap(),
-                params.text_document.version,
-            ),
-            content_changes: changes,
+        .map(|(file, changes)| {
+            let version = match state.open_files.get_mut(Path::new(&file)) {
+                Some(open_file) => open_file.next_version(),
+                None => params.text_document.version,
+            };
+            DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(
+                    Url::from_file_path(file).unwrap(),
+                    version,
+                ),
+                content_changes: changes,
+            }
+        })
+        .collect()
+}
+
+pub fn handle_did_save_text_document(
+    state: &mut GlobalState,
+    params: DidSaveTextDocumentParams,
+) -> Vec<DidSaveTextDocumentParams> {
+    let doc = &params.text_document;
+    if doc.uri.scheme() != "file" {
+        info!("DidSaveTextDocument: Encountered unsupported scheme {}.", doc.uri);
+        return vec![params];
+    }
+
+    let source_path = PathBuf::from(doc.uri.path());
+    // The saved content is preprocessed by the build.
+    state.pending_overlays.remove(&source_path);
+    if let Some(job) = state.overlay_jobs.get_mut(&source_path) {
+        job.discard = true;
+    }
+    if state.dirty_files.remove(&source_path) {
+        reload_source_file(state, &source_path);
+    }
+
+    let files = state.source_mapping.map_files(ToPreprocess, doc.uri.path());
+    if files.is_empty() {
+        warn!("DidSaveTextDocument: Encountered unknown file {}.", doc.uri.path());
+        return vec![params];
+    }
+
+    files
+        .iter()
+        .filter(|file| state.open_files.contains_key(*file))
+        .map(|file| DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: Url::from_file_path(file).unwrap() },
+            text: None,
         })
         .collect()
 }
@@ -106,14 +233,26 @@ pub fn handle_did_close_text_document(
         return vec![params];
     }
 
+    let source_path = PathBuf::from(doc.uri.path());
+    state.source_overlay.remove(&source_path);
+    state.pending_overlays.remove(&source_path);
+    if let Some(job) = state.overlay_jobs.get_mut(&source_path) {
+        job.discard = true;
+    }
+    if state.dirty_files.remove(&source_path) {
+        // Discard the mappings of unsaved changes.
+        reload_preprocessed_files(state, &files);
+    }
+
+    let files = state.source_mapping.map_files(ToPreprocess, doc.uri.path());
     let mut result = Vec::new();
     for file in files {
-        match state.open_files.get_mut(file) {
-            Some(count) => {
-                if *count > 1 {
+        match state.open_files.get_mut(&file) {
+            Some(open_file) => {
+                if open_file.count > 1 {
                     // Opened from other source file, do not send a close
                     // notification, just decrement the open count.
-                    *count -= 1;
+                    open_file.count -= 1;
                     continue;
                 }
             }
@@ -124,10 +263,561 @@ pub fn handle_did_close_text_document(
         }
 
         // Remove from opened files.
-        state.open_files.remove(file);
+        state.open_files.remove(&file);
         result.push(DidCloseTextDocumentParams {
-            text_document: TextDocumentIdentifier { uri: Url::from_file_path(file).unwrap() },
+            text_document: TextDocumentIdentifier { uri: Url::from_file_path(&file).unwrap() },
         });
     }
     result
 }
+
+/// Re-runs preprocess for the modules the (saved) source file belongs to, in all configurations.
+/// The preprocess worker reports back when it is done, see `reload_preprocessed_modules`.
+fn reload_source_file(state: &mut GlobalState, source_path: &Path) {
+    let mut found = false;
+    for (index, config) in state.configs.iter().enumerate() {
+        let modules = config.build_env.modules_for_source(source_path);
+        if modules.is_empty() {
+            continue;
+        }
+        found = true;
+
+        info!("Preprocess modules {} of {}", modules.join(", "), config.name);
+        let job = PreprocessJob::Modules {
+            config: index,
+            source: source_path.to_path_buf(),
+            preprocessor: config.build_env.preprocessor(),
+            modules,
+        };
+        state.preprocess_worker.sender().send(job).expect("Lost preprocess worker.");
+    }
+    if !found {
+        warn!("DidSaveTextDocument: No module found for {}.", source_path.display());
+    }
+}
+
+/// Handles the result of a preprocess run by the preprocess worker.
+pub fn handle_preprocess_event(state: &mut GlobalState, event: PreprocessEvent) {
+    match event {
+        PreprocessEvent::Modules { config, source, build_dir, result } => {
+            if state.configs[config].build_env.build_dir != build_dir {
+                info!("Drop preprocess result of replaced configuration {}.", build_dir.display());
+                return;
+            }
+            reload_preprocessed_modules(state, config, &source, result);
+        }
+        PreprocessEvent::Overlay { config, source, build_dir, result } => {
+            let Some(job) = state.overlay_jobs.get_mut(&source) else {
+                return;
+            };
+            job.remaining -= 1;
+            match result {
+                Ok(files) => job.generated.extend(files),
+                Err(err) => {
+                    warn!("{:#}", err);
+                    job.discard = true;
+                }
+            }
+            if state.configs[config].build_env.build_dir != build_dir {
+                // Preprocess again for the new configuration.
+                job.discard = true;
+                if state.source_overlay.contains_key(&source) {
+                    state.pending_overlays.insert(source.clone(), Instant::now());
+                }
+            }
+            if job.remaining == 0 {
+                let job = state.overlay_jobs.remove(&source).unwrap();
+                if !job.discard {
+                    apply_overlay(state, &source, job);
+                }
+            }
+        }
+    }
+}
+
+/// Reloads the line mappings of the files generated for the modules of a saved source file in a
+/// configuration, and re-opens them at the language server.
+fn reload_preprocessed_modules(
+    state: &mut GlobalState,
+    config: usize,
+    source_path: &Path,
+    result: Result<Vec<String>>,
+) {
+    if state.dirty_files.contains(source_path) {
+        // Changed again since it was saved, the generated files are outdated already. Preprocess
+        // the unsaved content instead.
+        state.pending_overlays.entry(source_path.to_path_buf()).or_insert_with(Instant::now);
+        return;
+    }
+
+    let source = source_path.to_str().unwrap();
+    let before = state.source_mapping.map_files(ToPreprocess, source);
+    let mut files: Vec<PathBuf> = before
+        .iter()
+        .filter(|file| state.source_mapping.config_of(file) == Some(config))
+        .cloned()
+        .collect();
+    match result {
+        Ok(modules) => {
+            for module in &modules {
+                for output in state.configs[config].build_env.module_outputs(module) {
+                    if !files.contains(&output) {
+                        files.push(output);
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            error!("{:#}", err);
+            let message = format!(
+                "Preprocessing {} for {} failed, line mapping is outdated.",
+                source, state.configs[config].name
+            );
+            state.show_message(MessageType::ERROR, message);
+        }
+    }
+
+    reload_preprocessed_files(state, &files);
+    update_open_files(state, source, &before);
+}
+
+/// Preprocesses the unsaved content of the source files that were not changed for
+/// `PREPROCESS_DELAY`, unless preprocess is still running for them.
+pub fn handle_pending_overlays(state: &mut GlobalState) {
+    let now = Instant::now();
+    let due: Vec<PathBuf> = state
+        .pending_overlays
+        .iter()
+        .filter(|(source, deadline)| **deadline <= now && !state.overlay_jobs.contains_key(*source))
+        .map(|(source, _)| source.clone())
+        .collect();
+    for source in due {
+        state.pending_overlays.remove(&source);
+        preprocess_overlay(state, &source);
+    }
+}
+
+/// Hands the unsaved content of the opened source files to the preprocess worker, to preprocess
+/// the modules the changed source file belongs to in all configurations, see `apply_overlay`.
+fn preprocess_overlay(state: &mut GlobalState, source_path: &Path) {
+    let mut remaining = 0;
+    for (index, config) in state.configs.iter().enumerate() {
+        let modules = config.build_env.modules_for_source(source_path);
+        if modules.is_empty() {
+            continue;
+        }
+        remaining += 1;
+
+        let job = PreprocessJob::Overlay {
+            config: index,
+            source: source_path.to_path_buf(),
+            preprocessor: config.build_env.preprocessor(),
+            modules,
+            sources: state.source_overlay.clone(),
+        };
+        state.preprocess_worker.sender().send(job).expect("Lost preprocess worker.");
+    }
+    if remaining == 0 {
+        warn!("DidChangeTextDocument: No module found for {}.", source_path.display());
+        return;
+    }
+
+    let job = OverlayJob {
+        remaining,
+        generated: Vec::new(),
+        sources: state.source_overlay.clone(),
+        changes: Vec::new(),
+        discard: false,
+    };
+    state.overlay_jobs.insert(source_path.to_path_buf(), job);
+}
+
+/// Sends the files generated from the unsaved content of a source file to the language server as
+/// full-document changes, followed by the changes made to the source file while preprocess ran.
+fn apply_overlay(state: &mut GlobalState, source_path: &Path, job: OverlayJob) {
+    let source = source_path.to_str().unwrap();
+    // The preprocessed files in the build directory are only updated on save.
+    state.dirty_files.insert(source_path.to_path_buf());
+    let before = state.source_mapping.map_files(ToPreprocess, source);
+    for (file, text) in &job.generated {
+        state.source_mapping.reload_file_from_text(file, text, &job.sources);
+    }
+    for (file, text) in job.generated {
+        state.preprocessed_overlay.insert(file.clone(), text);
+        if state.open_files.contains_key(&file) {
+            send_full_change(state, &file);
+        }
+    }
+    update_open_files(state, source, &before);
+
+    // The full-document changes replaced the changes forwarded meanwhile.
+    for params in job.changes {
+        for params in forward_changes(state, params) {
+            state
+                .send_to_server(build_notif::<DidChangeTextDocument>(params))
+                .expect("Lost connection to server.");
+        }
+    }
+}
+
+/// Opens (closes) the preprocessed files the source file now maps (no longer maps) to, compared
+/// to the preprocessed files it mapped to `before`.
+fn update_open_files(state: &mut GlobalState, source: &str, before: &[PathBuf]) {
+    let after = state.source_mapping.map_files(ToPreprocess, source);
+    let language_id = before
+        .iter()
+        .find_map(|file| state.open_files.get(file))
+        .map_or_else(|| "cpp".to_owned(), |open_file| open_file.language_id.clone());
+    for file in after.iter().filter(|file| !before.contains(file)) {
+        match state.open_files.get_mut(file) {
+            Some(open_file) => open_file.count += 1,
+            None => {
+                let open_file = OpenFile { count: 1, version: 0, language_id: language_id.clone() };
+                send_did_open(state, file, &open_file);
+                state.open_files.insert(file.clone(), open_file);
+            }
+        }
+    }
+    for file in before.iter().filter(|file| !after.contains(file)) {
+        if let Some(open_file) = state.open_files.get_mut(file) {
+            open_file.count -= 1;
+            if open_file.count == 0 {
+                state.open_files.remove(file);
+                send_did_close(state, file);
+            }
+        }
+    }
+}
+
+/// Reloads the line mappings of the given preprocessed files and re-opens those that are opened
+/// at the language server, so that it picks up their new content.
+pub fn reload_preprocessed_files(state: &mut GlobalState, files: &[PathBuf]) {
+    for file in files {
+        state.preprocessed_overlay.remove(file);
+        state.source_mapping.reload_file(file);
+    }
+
+    for file in files {
+        let Some(mut open_file) = state.open_files.remove(file) else {
+            continue;
+        };
+        open_file.next_version();
+        send_did_close(state, file);
+        send_did_open(state, file, &open_file);
+        state.open_files.insert(file.clone(), open_file);
+    }
+}
+
+/// Reloads the line mappings of preprocessed files that changed in the build directory of a
+/// configuration, e.g. by a build in a terminal, and sends the new content of opened files to the
+/// language server.
+pub fn handle_build_event(state: &mut GlobalState, config: usize, event: BuildEvent) {
+    match event {
+        BuildEvent::Preprocessed(files) => {
+            // Files generated from unsaved sources are updated once the sources are saved. Files
+            // that do not belong to a module are ignored.
+            let files: Vec<PathBuf> = files
+                .into_iter()
+                .filter(|file| !state.preprocessed_overlay.contains_key(file))
+                .filter(|file| state.source_mapping.module_of(file).is_some())
+                .collect();
+            info!("Reload {} changed preprocessed files.", files.len());
+            for file in &files {
+                state.source_mapping.reload_file(file);
+            }
+            for file in &files {
+                if state.open_files.contains_key(file) {
+                    send_full_change(state, file);
+                }
+            }
+        }
+        BuildEvent::Modules => {
+            let build_env = &mut state.configs[config].build_env;
+            build_env.forget_preprocess_commands();
+
+            let build_dir = build_env.build_dir.clone();
+            state.source_mapping.get_mut(config).reload_modules(&build_dir);
+
+            // Let the language server reload the compilation database.
+            let compile_commands = build_dir.join("compile_commands.json");
+            state
+                .send_to_config(
+                    config,
+                    build_notif::<DidChangeWatchedFiles>(DidChangeWatchedFilesParams {
+                        changes: vec![FileEvent {
+                            uri: Url::from_file_path(compile_commands).unwrap(),
+                            typ: FileChangeType::CHANGED,
+                        }],
+                    }),
+                )
+                .expect("Lost connection to server.");
+        }
+    }
+}
+
+/// Replaces a configuration by one for the given build directory, e.g. to switch to another
+/// Fiasco configuration, with a new language server. Requests pending at the old language server
+/// fail. The preprocessed files of the opened source files are opened at the new one.
+pub fn replace_configuration(
+    state: &mut GlobalState,
+    config: usize,
+    build_env: BuildEnv,
+) -> Result<()> {
+    build_env.gen_compile_commands()?;
+    let (new_config, _) =
+        Configuration::start(build_env, &state.settings, &state.initialize_params)?;
+    let build_dir = new_config.build_env.build_dir.clone();
+    let (source_mapping, report) = load_source_mapping(&build_dir);
+    info!("Replace configuration {} by {}.", state.configs[config].name, new_config.name);
+
+    let message = format!("Configuration {} was replaced.", state.configs[config].name);
+    state.fail_requests(config, &message);
+    diagnostics::clear_diagnostics(state, config);
+    // The documents opened at the old language server are gone with it.
+    state.open_files.retain(|file, _| state.source_mapping.config_of(file) != Some(config));
+    state
+        .preprocessed_overlay
+        .retain(|file, _| state.source_mapping.config_of(file) != Some(config));
+    state.configs[config] = new_config;
+    state.source_mapping.replace(config, &build_dir, source_mapping);
+    if !report.is_empty() {
+        let message = format!("{}: {}", state.configs[config].name, report.summary());
+        state.show_message(MessageType::WARNING, message);
+    }
+
+    reopen_files(state, config);
+    Ok(())
+}
+
+/// Restarts the language server of a configuration, e.g. after its command line changed or it
+/// crashed. Its pending requests fail with the given reason and its opened files are opened
+/// again at the new server.
+pub fn restart_server(state: &mut GlobalState, config: usize, reason: &str) -> Result<()> {
+    info!("Restart language server of configuration {}: {}", state.configs[config].name, reason);
+    state.fail_requests(config, reason);
+    let (server, _) =
+        start_server(&state.configs[config].build_env, &state.settings, &state.initialize_params)?;
+    state.configs[config].server = server;
+    state.configs[config].running = true;
+    state.configs[config].last_message = Instant::now();
+
+    let files: Vec<PathBuf> = state
+        .open_files
+        .keys()
+        .filter(|file| state.source_mapping.config_of(file) == Some(config))
+        .cloned()
+        .collect();
+    for file in &files {
+        let open_file = state.open_files.remove(file).unwrap();
+        send_did_open(state, file, &open_file);
+        state.open_files.insert(file.clone(), open_file);
+    }
+    Ok(())
+}
+
+/// Opens the preprocessed files of a configuration that the opened source files map to, e.g.
+/// after its language server was started.
+fn reopen_files(state: &mut GlobalState, config: usize) {
+    let sources: Vec<PathBuf> = state.source_overlay.keys().cloned().collect();
+    for source in &sources {
+        let files = state
+            .source_mapping
+            .get(config)
+            .map_files(ToPreprocess, source.to_str().unwrap())
+            .to_vec();
+        for file in files {
+            match state.open_files.get_mut(&file) {
+                Some(open_file) => open_file.count += 1,
+                None => {
+                    let open_file =
+                        OpenFile { count: 1, version: 0, language_id: "cpp".to_owned() };
+                    send_did_open(state, &file, &open_file);
+                    state.open_files.insert(file, open_file);
+                }
+            }
+        }
+    }
+
+    // The preprocessed files in the build directory do not contain unsaved changes.
+    for source in &state.dirty_files {
+        state.pending_overlays.insert(source.clone(), Instant::now());
+    }
+}
+
+/// Sends the current content of an opened preprocessed file to the language server.
+fn send_full_change(state: &mut GlobalState, file: &Path) {
+    let text = match state.preprocessed_overlay.get(file) {
+        Some(text) => text.clone(),
+        None => match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(err) => {
+                error!("Unable to read {}: {}", file.display(), err);
+                return;
+            }
+        },
+    };
+    let Some(open_file) = state.open_files.get_mut(file) else {
+        return;
+    };
+    let change = TextDocumentContentChangeEvent { range: None, range_length: None, text };
+    let params = DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier::new(
+            Url::from_file_path(file).unwrap(),
+            open_file.next_version(),
+        ),
+        content_changes: vec![change],
+    };
+    state
+        .send_to_server(build_notif::<DidChangeTextDocument>(params))
+        .expect("Lost connection to server.");
+}
+
+fn send_did_open(state: &mut GlobalState, file: &Path, open_file: &OpenFile) {
+    let text = match state.preprocessed_overlay.get(file) {
+        Some(text) => text.clone(),
+        None => match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(err) => {
+                error!("Unable to read {}: {}", file.display(), err);
+                return;
+            }
+        },
+    };
+    state
+        .send_to_server(build_notif::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: Url::from_file_path(file).unwrap(),
+                language_id: open_file.language_id.clone(),
+                version: open_file.version,
+                text,
+            },
+        }))
+        .expect("Lost connection to server.");
+}
+
+fn send_did_close(state: &mut GlobalState, file: &Path) {
+    state
+        .send_to_server(build_notif::<DidCloseTextDocument>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: Url::from_file_path(file).unwrap() },
+        }))
+        .expect("Lost connection to server.");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lsp_server::Message;
+    use lsp_types::Position;
+
+    use super::*;
+    use crate::source_mapping::MapDirection::FromPreprocess;
+
+    #[test]
+    fn preprocess_on_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        fs::write(&source, "a;\nb;\n").unwrap();
+        let build_dir = dir.path().join("build");
+        let preprocessed = build_dir.join("auto/foo.cpp");
+        fs::create_dir_all(build_dir.join("auto")).unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        fs::write(&preprocessed, format!("#line 1 \"{}\"\na;\n", source.display())).unwrap();
+        let makefile = format!(
+            "auto/stamp-foo.ready:\n\tprintf '#line 2 \"{}\"\\nb;\\n' > auto/foo.cpp\n",
+            source.display()
+        );
+        fs::write(build_dir.join("Makefile"), makefile).unwrap();
+        let (mut state, client) = GlobalState::for_test(&[&build_dir]);
+        let save = || DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: Url::from_file_path(&source).unwrap() },
+            text: None,
+        };
+        let line = |state: &GlobalState| {
+            state.source_mapping.map(FromPreprocess, preprocessed.to_str().unwrap(), 1, 0).line
+        };
+
+        state.dirty_files.insert(source.clone());
+        handle_did_save_text_document(&mut state, save());
+        // Reloaded once the worker is done, not while handling the save.
+        assert_eq!(line(&state), 0);
+        let event = state.preprocess_worker.receiver().recv_timeout(Duration::from_secs(10));
+        handle_preprocess_event(&mut state, event.unwrap());
+        assert_eq!(line(&state), 1);
+
+        // A failure is shown to the user.
+        fs::write(build_dir.join("Makefile"), "auto/stamp-foo.ready:\n\tfalse\n").unwrap();
+        state.dirty_files.insert(source.clone());
+        handle_did_save_text_document(&mut state, save());
+        let event = state.preprocess_worker.receiver().recv_timeout(Duration::from_secs(10));
+        handle_preprocess_event(&mut state, event.unwrap());
+        let msg = client.receiver.try_recv().unwrap();
+        let Message::Notification(not) = msg else {
+            panic!("Unexpected message {msg:?}");
+        };
+        assert_eq!(not.method, "window/showMessage");
+    }
+
+    #[test]
+    fn preprocess_overlay_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("foo.cpp");
+        fs::write(&source, "a;\n").unwrap();
+        let build_dir = dir.path().join("build");
+        let preprocessed = build_dir.join("auto/foo.cpp");
+        fs::create_dir_all(build_dir.join("auto")).unwrap();
+        fs::write(build_dir.join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        let line = format!("#line 1 \"{}\"\n", source.display());
+        fs::write(&preprocessed, format!("{line}a;\n")).unwrap();
+        // Stands in for preprocess: `preprocess -c <output base> <source>`.
+        let tool = build_dir.join("preprocess");
+        fs::write(&tool, "#!/bin/sh\n{ echo \"#line 1 \\\"$3\\\"\"; cat \"$3\"; } > \"$2.cpp\"\n")
+            .unwrap();
+        fs::set_permissions(&tool, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let makefile =
+            format!("auto/stamp-foo.ready:\n\t./preprocess -c auto/foo {}\n", source.display());
+        fs::write(build_dir.join("Makefile"), makefile).unwrap();
+        let (mut state, _client) = GlobalState::for_test(&[&build_dir]);
+        let uri = Url::from_file_path(&source).unwrap();
+        let change = |version, range, text: &str| DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range,
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        };
+
+        let item = TextDocumentItem::new(uri.clone(), "cpp".to_owned(), 0, "a;\n".to_owned());
+        handle_did_open_text_document(
+            &mut state,
+            DidOpenTextDocumentParams { text_document: item },
+        );
+        handle_did_change_text_document(&mut state, change(1, None, "a;\nb;\n"));
+        state.pending_overlays.insert(source.clone(), Instant::now());
+        handle_pending_overlays(&mut state);
+        assert!(state.overlay_jobs.contains_key(&source));
+        // Changed again while preprocess runs.
+        let range = lsp_types::Range::new(Position::new(0, 0), Position::new(0, 0));
+        handle_did_change_text_document(&mut state, change(2, Some(range), "c;\n"));
+
+        let event = state.preprocess_worker.receiver().recv_timeout(Duration::from_secs(10));
+        handle_preprocess_event(&mut state, event.unwrap());
+        assert!(state.overlay_jobs.is_empty());
+        assert_eq!(state.preprocessed_overlay[&preprocessed], format!("{line}a;\nb;\n"));
+        // The generated file is sent, followed by the change made meanwhile.
+        let server = state.configs[0].server.from_lang_server.receiver();
+        let mut changes = Vec::new();
+        while let Ok(msg) = server.recv_timeout(Duration::from_secs(1)) {
+            if let Message::Notification(not) = msg {
+                let params: DidChangeTextDocumentParams =
+                    serde_json::from_value(not.params).unwrap();
+                changes.extend(params.content_changes);
+            }
+        }
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].text, format!("{line}a;\nb;\n"));
+        assert_eq!(changes[1].range.unwrap().start.line, 1);
+        assert_eq!(changes[1].text, "c;\n");
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use lsp_types::{ExecuteCommandParams, MessageType};
+use serde_json::Value;
+
+use crate::build_env::BuildEnv;
+use crate::configuration::PRIMARY;
+use crate::global_state::GlobalState;
+use crate::handler::document_sync;
+
+/// Switches the primary configuration to another build directory, given as argument.
+pub const SELECT_CONFIGURATION: &str = "fiasco.selectConfiguration";
+
+/// Adds the commands handled by the proxy to the capabilities returned by the language server.
+pub fn add_commands(initialize_result: &mut Value) {
+    let commands = &mut initialize_result["capabilities"]["executeCommandProvider"]["commands"];
+    if !commands.is_array() {
+        *commands = Value::Array(Vec::new());
+    }
+    commands.as_array_mut().unwrap().push(SELECT_CONFIGURATION.into());
+}
+
+/// Handles the commands of the proxy, other commands are forwarded to the language server.
+pub fn handle_execute_command(
+    state: &mut GlobalState,
+    params: &ExecuteCommandParams,
+) -> Option<Result<Option<Value>, String>> {
+    match params.command.as_str() {
+        SELECT_CONFIGURATION => Some(select_configuration(state, &params.arguments).map(|_| None)),
+        _ => None,
+    }
+}
+
+fn select_configuration(state: &mut GlobalState, arguments: &[Value]) -> Result<(), String> {
+    let Some(build_dir) = arguments.first().and_then(Value::as_str) else {
+        return Err(format!("{SELECT_CONFIGURATION} expects the build directory as argument."));
+    };
+    let build_dir = Path::new(build_dir)
+        .canonicalize()
+        .map_err(|err| format!("Invalid build directory {build_dir}: {err}"))?;
+    let served = state.configs.iter().position(|config| {
+        config.build_env.build_dir.canonicalize().is_ok_and(|dir| dir == build_dir)
+    });
+    if served.is_some_and(|config| config != PRIMARY) {
+        return Err(format!("{} is already served as another configuration.", build_dir.display()));
+    }
+
+    document_sync::replace_configuration(state, PRIMARY, BuildEnv::from_dir(&build_dir))
+        .map_err(|err| format!("{err:#}"))?;
+    let message = format!("Switched to configuration {}.", state.configs[PRIMARY].name);
+    state.show_message(MessageType::INFO, message);
+    Ok(())
+}
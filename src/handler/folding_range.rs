@@ -0,0 +1,36 @@
+use lsp_types::request::FoldingRangeRequest;
+use lsp_types::{FoldingRange, FoldingRangeParams, Position, Range, TextDocumentIdentifier};
+
+use crate::global_state::GlobalState;
+use crate::handler::document_split::{DocumentSplit, SplitFile};
+
+impl DocumentSplit for FoldingRangeRequest {
+    type Item = FoldingRange;
+
+    fn text_document(params: &mut FoldingRangeParams) -> &mut TextDocumentIdentifier {
+        &mut params.text_document
+    }
+
+    fn into_items(result: Option<Vec<FoldingRange>>) -> Vec<FoldingRange> {
+        result.unwrap_or_default()
+    }
+
+    fn from_items(items: Vec<FoldingRange>) -> Option<Vec<FoldingRange>> {
+        Some(items)
+    }
+
+    fn map_item(state: &mut GlobalState, file: &SplitFile, folding: &mut FoldingRange) -> bool {
+        let mut range = Range::new(
+            Position::new(folding.start_line, folding.start_character.unwrap_or(0)),
+            Position::new(folding.end_line, folding.end_character.unwrap_or(0)),
+        );
+        if !file.map_range(state, &mut range) {
+            return false;
+        }
+        folding.start_line = range.start.line;
+        folding.start_character = folding.start_character.map(|_| range.start.character);
+        folding.end_line = range.end.line;
+        folding.end_character = folding.end_character.map(|_| range.end.character);
+        true
+    }
+}
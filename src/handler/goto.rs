@@ -1,7 +1,8 @@
-use lsp_types::{GotoDefinitionResponse, Location};
+use lsp_types::{GotoDefinitionResponse, Location, LocationLink};
 
 use crate::global_state::{GlobalState, ReqContext};
 use crate::source_mapping::MapDirection::FromPreprocess;
+use crate::util::dedup;
 
 pub fn handle_res_goto(
     state: &mut GlobalState,
@@ -15,7 +16,9 @@ pub fn handle_res_goto(
     let mut result = res?;
     match &mut result {
         GotoDefinitionResponse::Scalar(location) => {
-            state.source_mapping.map_location(FromPreprocess, location);
+            if state.source_mapping.map_location(FromPreprocess, location).is_err() {
+                warn!("GotoRequest: Encountered unmappable location {:?}.", &location);
+            }
         }
         GotoDefinitionResponse::Array(vec) => vec.retain_mut(|location| {
             state.source_mapping.map_location(FromPreprocess, location).is_ok()
@@ -23,8 +26,16 @@ pub fn handle_res_goto(
         GotoDefinitionResponse::Link(vec) => vec.retain_mut(|location| {
             let mut path = mapped_file.clone();
             if let Some(origin_selection_range) = location.origin_selection_range.as_mut() {
-                state.source_mapping.map_range(FromPreprocess, &mut path, origin_selection_range);
-                if source_path != path {
+                if state
+                    .source_mapping
+                    .map_range(FromPreprocess, &mut path, origin_selection_range)
+                    .is_err()
+                {
+                    warn!(
+                        "GotoRequest: Encountered unmappable origin_selection_range {:?}.",
+                        &origin_selection_range
+                    );
+                } else if source_path != path {
                     warn!(
                         "GotoRequest: Origin selection mapped to different file ({}) than source file specified in request ({}).",
                         &path, &source_path
@@ -84,3 +95,48 @@ pub fn handle_res_references(
         .retain_mut(|location| state.source_mapping.map_location(FromPreprocess, location).is_ok());
     Some(result)
 }
+
+pub fn merge_goto(results: Vec<Option<GotoDefinitionResponse>>) -> Option<GotoDefinitionResponse> {
+    let mut results: Vec<GotoDefinitionResponse> = results.into_iter().flatten().collect();
+    if results.len() <= 1 {
+        return results.pop();
+    }
+
+    if results.iter().any(|result| matches!(result, GotoDefinitionResponse::Link(_))) {
+        let links = results
+            .into_iter()
+            .flat_map(|result| match result {
+                GotoDefinitionResponse::Scalar(location) => vec![location_link(location)],
+                GotoDefinitionResponse::Array(locations) => {
+                    locations.into_iter().map(location_link).collect()
+                }
+                GotoDefinitionResponse::Link(links) => links,
+            })
+            .collect();
+        Some(GotoDefinitionResponse::Link(dedup(links)))
+    } else {
+        let locations = results
+            .into_iter()
+            .flat_map(|result| match result {
+                GotoDefinitionResponse::Scalar(location) => vec![location],
+                GotoDefinitionResponse::Array(locations) => locations,
+                GotoDefinitionResponse::Link(_) => unreachable!(),
+            })
+            .collect();
+        Some(GotoDefinitionResponse::Array(dedup(locations)))
+    }
+}
+
+fn location_link(location: Location) -> LocationLink {
+    LocationLink {
+        origin_selection_range: None,
+        target_uri: location.uri,
+        target_range: location.range,
+        target_selection_range: location.range,
+    }
+}
+
+pub fn merge_references(results: Vec<Option<Vec<Location>>>) -> Option<Vec<Location>> {
+    let locations: Vec<Location> = results.into_iter().flatten().flatten().collect();
+    Some(dedup(locations))
+}
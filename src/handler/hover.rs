@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use lsp_types::{Hover, HoverContents, MarkedString, MarkupContent, MarkupKind};
+
+use crate::global_state::{GlobalState, ReqContext};
+use crate::source_mapping::MapDirection::FromPreprocess;
+use crate::util::dedup;
+
+pub fn handle_res_hover(
+    state: &mut GlobalState,
+    req_context: &mut ReqContext,
+    res: Option<Hover>,
+) -> Option<Hover> {
+    let (source_path, mapped_file) = match req_context.take_value::<(String, String)>() {
+        None => return res,
+        Some(t) => t,
+    };
+    let mut result = res?;
+    if let Some(tag) = state.config_tag(Path::new(&mapped_file)) {
+        tag_hover(&mut result, tag);
+    }
+    if let Some(range) = result.range.as_mut() {
+        let mut path = mapped_file;
+        if state.source_mapping.map_range(FromPreprocess, &mut path, range).is_err()
+            || path != source_path
+        {
+            warn!("HoverRequest: Dropped range that cannot be mapped to {}.", source_path);
+            result.range = None;
+        }
+    }
+    Some(result)
+}
+
+/// Prefixes the contents of a hover with the name of the configuration it is from.
+fn tag_hover(hover: &mut Hover, tag: &str) {
+    match &mut hover.contents {
+        HoverContents::Markup(markup) if markup.kind == MarkupKind::Markdown => {
+            markup.value = format!("*{}*\n\n{}", tag, markup.value)
+        }
+        HoverContents::Markup(markup) => markup.value = format!("[{}]\n{}", tag, markup.value),
+        HoverContents::Scalar(string) => {
+            let strings = vec![MarkedString::String(format!("[{}]", tag)), string.clone()];
+            hover.contents = HoverContents::Array(strings);
+        }
+        HoverContents::Array(strings) => {
+            strings.insert(0, MarkedString::String(format!("[{}]", tag)))
+        }
+    }
+}
+
+pub fn merge_hover(results: Vec<Option<Hover>>) -> Option<Hover> {
+    let mut hovers = dedup(results.into_iter().flatten().collect());
+    if hovers.len() <= 1 {
+        return hovers.pop();
+    }
+
+    let range = hovers.iter().find_map(|hover| hover.range);
+    let all_markup = hovers.iter().all(|hover| matches!(hover.contents, HoverContents::Markup(_)));
+    let contents = if all_markup {
+        let mut kind = MarkupKind::Markdown;
+        let values: Vec<String> = hovers
+            .into_iter()
+            .filter_map(|hover| match hover.contents {
+                HoverContents::Markup(markup) => {
+                    if markup.kind == MarkupKind::PlainText {
+                        kind = MarkupKind::PlainText;
+                    }
+                    Some(markup.value)
+                }
+                _ => None,
+            })
+            .collect();
+        let separator = if kind == MarkupKind::Markdown { "\n\n---\n\n" } else { "\n\n" };
+        HoverContents::Markup(MarkupContent { kind, value: values.join(separator) })
+    } else {
+        let strings = hovers
+            .into_iter()
+            .flat_map(|hover| match hover.contents {
+                HoverContents::Scalar(string) => vec![string],
+                HoverContents::Array(strings) => strings,
+                HoverContents::Markup(markup) => vec![MarkedString::String(markup.value)],
+            })
+            .collect();
+        HoverContents::Array(dedup(strings))
+    };
+    Some(Hover { contents, range })
+}
@@ -1,8 +1,20 @@
+pub mod cancel;
+pub mod change_configuration;
 pub mod code_action;
+pub mod completion;
 pub mod diagnostics;
+pub mod document_color;
 pub mod document_highlight;
+pub mod document_link;
+pub mod document_split;
 pub mod document_symbol;
 pub mod document_sync;
+pub mod execute_command;
+pub mod folding_range;
 pub mod goto;
+pub mod hover;
 pub mod inlay_hint;
+pub mod pending_requests;
+pub mod recovery;
+pub mod server_log;
 pub mod source_location;
@@ -0,0 +1,149 @@
+//! Expiry and listing of the pending requests.
+
+use lsp_server::{ErrorCode, RequestId, Response, ResponseError};
+use lsp_types::request::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::global_state::{Direction, GlobalState, REQUEST_TIMEOUT};
+use crate::handler::cancel;
+
+/// Lists the pending requests, to debug hanging requests.
+pub enum PendingRequests {}
+
+impl Request for PendingRequests {
+    type Params = ();
+    type Result = Vec<PendingRequest>;
+    const METHOD: &'static str = "fiasco/pendingRequests";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingRequest {
+    /// Sender of the request, `client` or `server`.
+    pub from: String,
+    pub method: String,
+    /// Id of the request at its sender.
+    pub id: RequestId,
+    /// Id the request was sent on with.
+    pub sent_id: RequestId,
+    /// Configuration of the language server the request was sent to, or received from.
+    pub configuration: String,
+    /// Milliseconds since the request was received.
+    pub age_ms: u64,
+    /// Whether the request was cancelled or expired, its response is still waited for to drop
+    /// it.
+    pub cancelled: bool,
+}
+
+pub fn handle_pending_requests(
+    state: &mut GlobalState,
+    _params: &(),
+) -> Option<Result<Vec<PendingRequest>, String>> {
+    let mut pending = Vec::new();
+    for (direction, reqs) in
+        [(Direction::ToServer, &state.client_reqs), (Direction::FromServer, &state.server_reqs)]
+    {
+        for (sent_id, req_context) in reqs.iter() {
+            pending.push(PendingRequest {
+                from: direction.to_string(),
+                method: req_context.method().to_owned(),
+                id: req_context.req_id().clone(),
+                sent_id: sent_id.clone(),
+                configuration: state.configs[req_context.config()].name.clone(),
+                age_ms: req_context.age().as_millis() as u64,
+                cancelled: req_context.cancelled(),
+            });
+        }
+    }
+    pending.sort_by_key(|req| std::cmp::Reverse(req.age_ms));
+    Some(Ok(pending))
+}
+
+/// Answers the requests that are pending for too long with an error and cancels them at their
+/// receiver. Forgets about cancelled requests whose response did not come in.
+pub fn handle_expired_requests(state: &mut GlobalState) {
+    for direction in [Direction::ToServer, Direction::FromServer] {
+        let expired: Vec<(RequestId, RequestId, usize)> = state
+            .reqs(direction)
+            .iter()
+            .filter(|(_, req_context)| req_context.expired())
+            .map(|(id, req_context)| {
+                (id.clone(), req_context.req_id().clone(), req_context.config())
+            })
+            .collect();
+        for (id, req_id, config) in expired {
+            warn!("Request {} from {} expired.", req_id, direction);
+            cancel::cancel_sent_request(state, direction, &id);
+            let error = ResponseError {
+                code: ErrorCode::RequestFailed as i32,
+                message: format!(
+                    "Request was not answered within {} seconds.",
+                    REQUEST_TIMEOUT.as_secs()
+                ),
+                data: None,
+            };
+            // Only requests from the client are aggregated.
+            let aggregation = match direction {
+                Direction::ToServer => state.aggregations.get_mut(&req_id),
+                Direction::FromServer => None,
+            };
+            match aggregation {
+                Some(aggregation) => {
+                    aggregation.add_error(error);
+                    state.answer_aggregation(&req_id);
+                }
+                None => {
+                    let res = Response { id: req_id, result: None, error: Some(error) };
+                    state
+                        .send_to(direction.reverse(), config, res)
+                        .unwrap_or_else(|_| panic!("Lost connection to {}.", direction.reverse()));
+                }
+            }
+        }
+
+        state.reqs(direction).retain(|_, req_context| !req_context.forgotten());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::global_state::ReqContext;
+
+    #[test]
+    fn list_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut state, _client) = GlobalState::for_test(&[dir.path()]);
+        let reqs = [
+            (Direction::ToServer, "textDocument/hover", 1),
+            (Direction::FromServer, "workspace/applyEdit", 2),
+            (Direction::ToServer, "textDocument/definition", 3),
+        ];
+        for (direction, method, id) in reqs {
+            let mut req_context = ReqContext::new(method.to_owned(), RequestId::from(id));
+            if id == 3 {
+                req_context.cancel();
+            }
+            state.reqs(direction).insert(RequestId::from(id + 10), req_context);
+            sleep(Duration::from_millis(5));
+        }
+
+        let pending = handle_pending_requests(&mut state, &()).unwrap().unwrap();
+        assert!(pending.iter().all(|req| req.configuration == state.configs[0].name));
+        let pending: Vec<_> = pending
+            .iter()
+            .map(|req| (req.from.as_str(), req.method.as_str(), req.sent_id.clone(), req.cancelled))
+            .collect();
+        assert_eq!(
+            pending,
+            [
+                ("client", "textDocument/hover", RequestId::from(11), false),
+                ("server", "workspace/applyEdit", RequestId::from(12), false),
+                ("client", "textDocument/definition", RequestId::from(13), true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Restarts language servers that crashed or hang.
+
+use std::time::{Duration, Instant};
+
+use lsp_types::MessageType;
+
+use crate::global_state::GlobalState;
+use crate::handler::document_sync;
+
+/// How often the pending requests are checked for a hanging language server.
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A language server that did not answer a request for this long, and did not send any other
+/// message meanwhile, is considered hanging. It is restarted, which fails its pending requests,
+/// see also `REQUEST_TIMEOUT`.
+pub const HANG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A language server that was restarted this often within `RESTART_WINDOW` is given up.
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(120);
+
+/// Handles the exit of the language server of a configuration.
+pub fn handle_server_exit(state: &mut GlobalState, config: usize) {
+    let reason = format!("clangd of {} exited unexpectedly", state.configs[config].name);
+    error!("{}", reason);
+    restart(state, config, reason);
+}
+
+/// Restarts the language servers that did not answer a request in time.
+pub fn handle_watchdog(state: &mut GlobalState) {
+    let now = Instant::now();
+    for config in 0..state.configs.len() {
+        if is_hanging(state, config, now) {
+            let reason = format!(
+                "clangd of {} did not answer for {} seconds",
+                state.configs[config].name,
+                HANG_TIMEOUT.as_secs()
+            );
+            warn!("{}", reason);
+            restart(state, config, reason);
+        }
+    }
+}
+
+/// Returns whether the language server of a configuration has a request pending for longer than
+/// `HANG_TIMEOUT` and sent nothing in that time. A server that still answers other requests is
+/// only slow with that request.
+fn is_hanging(state: &GlobalState, config: usize, now: Instant) -> bool {
+    let configuration = &state.configs[config];
+    configuration.running
+        && now.duration_since(configuration.last_message) > HANG_TIMEOUT
+        && state.client_reqs.values().any(|req_context| {
+            req_context.config() == config
+                && !req_context.cancelled()
+                && req_context.age_at(now) > HANG_TIMEOUT
+        })
+}
+
+fn restart(state: &mut GlobalState, config: usize, reason: String) {
+    let now = Instant::now();
+    let restarts = &mut state.configs[config].restarts;
+    restarts.retain(|restart| now.duration_since(*restart) < RESTART_WINDOW);
+    if restarts.len() >= MAX_RESTARTS {
+        state.configs[config].running = false;
+        state.fail_requests(config, &format!("{reason}."));
+        let message = format!(
+            "{reason} and is not restarted anymore, as it was restarted {MAX_RESTARTS} times \
+            already. Change the clangd settings to start it again."
+        );
+        state.show_message(MessageType::ERROR, message);
+        return;
+    }
+    restarts.push(now);
+
+    match document_sync::restart_server(state, config, &format!("{reason}.")) {
+        Ok(()) => state.show_message(MessageType::WARNING, format!("{reason}, restarted it.")),
+        Err(err) => {
+            state.configs[config].running = false;
+            let message = format!("{reason}, failed to restart it: {err:#}");
+            state.show_message(MessageType::ERROR, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_server::RequestId;
+
+    use super::*;
+    use crate::global_state::ReqContext;
+
+    #[test]
+    fn hanging_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut state, _client) = GlobalState::for_test(&[dir.path()]);
+        let now = Instant::now();
+        let later = now + HANG_TIMEOUT + Duration::from_secs(1);
+        assert!(!is_hanging(&state, 0, later));
+
+        let req_context = ReqContext::new("textDocument/hover".to_owned(), RequestId::from(1));
+        state.client_reqs.insert(RequestId::from(1), req_context);
+        assert!(!is_hanging(&state, 0, now));
+        assert!(is_hanging(&state, 0, later));
+        // Still answers other requests.
+        state.configs[0].last_message = now + Duration::from_secs(30);
+        assert!(!is_hanging(&state, 0, later));
+    }
+}
@@ -0,0 +1,33 @@
+use lsp_server::Message;
+use lsp_types::notification::LogMessage;
+use lsp_types::{LogMessageParams, MessageType};
+
+use crate::global_state::GlobalState;
+use crate::util::build_notif;
+
+/// Guesses the severity of a line of the clangd log from its prefix, e.g.
+/// `E[12:34:56.789] Failed to find compilation database`. Verbose (`V[`) lines and lines without
+/// prefix, e.g. stack dumps, are plain log messages.
+fn message_type(line: &str) -> MessageType {
+    match line.get(..2) {
+        Some("E[") => MessageType::ERROR,
+        Some("I[") => MessageType::INFO,
+        _ => MessageType::LOG,
+    }
+}
+
+/// Forwards a line the language server of a configuration wrote to stderr to the client as
+/// `window/logMessage`, and to the websocket logger.
+pub fn handle_server_stderr(state: &mut GlobalState, config: usize, line: String) {
+    let typ = message_type(&line);
+    let message = match state.configs.len() {
+        1 => line,
+        _ => format!("[{}] {}", state.configs[config].name, line),
+    };
+    let notif = build_notif::<LogMessage>(LogMessageParams { typ, message });
+    let msg = Message::Notification(notif);
+    if let Err(err) = state.log_from_server(&msg) {
+        warn!("{:#}", err);
+    }
+    state.send_to_client(msg).expect("Lost connection to client.");
+}
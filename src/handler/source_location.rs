@@ -1,3 +1,12 @@
+use std::path::PathBuf;
+
+use lsp_types::request::Request;
+use lsp_types::{Position, TextDocumentPositionParams, Url};
+
+use crate::global_state::{GlobalState, ReqContext, ReqContextAlloc};
+use crate::source_mapping::MapDirection::ToPreprocess;
+use crate::source_mapping::SourceLocation;
+
 #[macro_export]
 macro_rules! handle_source_location {
     ($path:ident) => {
@@ -37,3 +46,64 @@ macro_rules! handle_reverse_source_location {
         }
     };
 }
+
+#[macro_export]
+macro_rules! handle_source_location_many {
+    ($path:ident, $request:ty) => {
+        |state: &mut GlobalState, req_context_alloc: &ReqContextAlloc, params| {
+            source_location::split_source_location::<$request>(
+                state,
+                req_context_alloc,
+                params,
+                |params| &mut params.$path,
+            )
+        }
+    };
+}
+
+/// Splits up a request for a source location into one request per preprocessed location the
+/// source location is mapped to. The request contexts hold the source file and the preprocessed
+/// file of each request.
+pub fn split_source_location<R>(
+    state: &mut GlobalState,
+    req_context_alloc: &ReqContextAlloc,
+    mut params: R::Params,
+    position: fn(&mut R::Params) -> &mut TextDocumentPositionParams,
+) -> Vec<(R::Params, ReqContext)>
+where
+    R: Request,
+    R::Params: Clone,
+{
+    let param = position(&mut params);
+    if param.text_document.uri.scheme() != "file" {
+        return vec![(params, req_context_alloc.alloc())];
+    }
+
+    let source_file = param.text_document.uri.path().to_owned();
+    let Position { line, character } = param.position;
+    let mut locations: Vec<SourceLocation> = state
+        .source_mapping
+        .map_all(ToPreprocess, &source_file, line, character)
+        .into_iter()
+        .map(|(location, _)| location)
+        .collect();
+    if locations.is_empty() {
+        debug!("No mapping found for {}:{}, forward as is.", source_file, line);
+        locations.push(SourceLocation { path: PathBuf::from(&source_file), line, character });
+    }
+
+    locations
+        .into_iter()
+        .map(|location| {
+            let mut req_params = params.clone();
+            let param = position(&mut req_params);
+            param.text_document.uri = Url::from_file_path(&location.path).unwrap();
+            param.position = Position::new(location.line, location.character);
+
+            let mut req_context = req_context_alloc.alloc();
+            req_context
+                .set_value((source_file.clone(), location.path.to_str().unwrap().to_owned()));
+            (req_params, req_context)
+        })
+        .collect()
+}
@@ -1,5 +1,6 @@
 //! Derived from: https://github.com/kak-lsp/kak-lsp/blob/master/src/language_server_transport.rs
-use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::fmt::Debug;
+use std::io::{self, BufRead, BufReader, BufWriter, Error, Result, Write};
 use std::process::{Command, Stdio};
 
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
@@ -9,20 +10,43 @@ use crate::thread_worker::Worker;
 
 pub enum Void {}
 
-pub struct LanguageServerTransport {
+/// Message framed by a `Content-Length` header, as used by LSP and DAP.
+pub trait TransportMessage: Debug + Send + Sized + 'static {
+    /// Reads the next message, returns `None` at the end of the stream.
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>>;
+    fn write(&self, writer: &mut impl Write) -> io::Result<()>;
+}
+
+impl TransportMessage for Message {
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        Message::read(reader)
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        Message::write(self, writer)
+    }
+}
+
+pub struct LanguageServerTransport<M = Message> {
     // The field order is important as it defines the order of drop.
     // We want to exit a writer loop first (after sending exit notification),
     // then close all pipes and wait until child process is finished.
     // That helps to ensure that reader loop is not stuck trying to read from the language server.
-    pub to_lang_server: Worker<Message, Void>,
-    pub from_lang_server: Worker<Void, Message>,
-    pub errors: Worker<Void, Void>,
+    pub to_lang_server: Worker<M, Void>,
+    pub from_lang_server: Worker<Void, M>,
+    /// Lines the language server writes to stderr, i.e. its log.
+    pub stderr: Worker<Void, String>,
 }
 
-pub fn start(cmd: &str, args: &[&str]) -> Result<LanguageServerTransport> {
+pub fn start<M: TransportMessage>(
+    cmd: &str,
+    args: &[&str],
+    envs: &[(&str, &str)],
+) -> Result<LanguageServerTransport<M>> {
     info!("Starting Language server `{} {}`", cmd, args.join(" "));
     let mut child = Command::new(cmd)
         .args(args)
+        .envs(envs.iter().copied())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -34,26 +58,34 @@ pub fn start(cmd: &str, args: &[&str]) -> Result<LanguageServerTransport> {
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
-    // XXX temporary way of tracing language server errors
     let mut stderr = BufReader::new(child.stderr.take().expect("Failed to open stderr"));
-    let errors =
-        Worker::spawn("Language server errors", channel_capacity, move |receiver, _| loop {
-            if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
-                return;
-            }
-            let mut buf = String::new();
-            match stderr.read_to_string(&mut buf) {
-                Ok(_) => {
-                    if buf.is_empty() {
-                        return;
+    let stderr =
+        Worker::spawn("Language server stderr", channel_capacity, move |receiver, sender| {
+            // Read up to the end even if nobody listens anymore, the server would block on a
+            // full pipe otherwise.
+            let mut forward = true;
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match stderr.read_until(b'\n', &mut buf) {
+                    Ok(0) => break,
+                    Ok(_) if !forward => {}
+                    Ok(_) => {
+                        let line = String::from_utf8_lossy(&buf);
+                        let line = line.trim_end_matches(['\n', '\r']);
+                        debug!("Language server stderr: {}", line);
+                        forward = !matches!(receiver.try_recv(), Err(TryRecvError::Disconnected))
+                            && sender.send(line.to_owned()).is_ok();
+                    }
+                    Err(e) => {
+                        error!("Failed to read from language server stderr: {}", e);
+                        break;
                     }
-                    error!("Language server error: {}", buf);
-                }
-                Err(e) => {
-                    error!("Failed to read from language server stderr: {}", e);
-                    return;
                 }
             }
+            // Keep the channel open until the transport is dropped, the server might run on
+            // without stderr.
+            let _ = receiver.recv();
         });
 
     let from_lang_server = Worker::spawn(
@@ -92,37 +124,33 @@ pub fn start(cmd: &str, args: &[&str]) -> Result<LanguageServerTransport> {
             }
         });
 
-    Ok(LanguageServerTransport { to_lang_server, from_lang_server, errors })
+    Ok(LanguageServerTransport { to_lang_server, from_lang_server, stderr })
 }
 
-fn reader_loop(
+pub fn reader_loop<M: TransportMessage>(
     mut reader: impl BufRead,
     receiver: Receiver<Void>,
-    sender: &Sender<Message>,
+    sender: &Sender<M>,
 ) -> io::Result<()> {
     loop {
         if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
             return Ok(());
         }
-        while let Some(msg) = Message::read(&mut reader)? {
-            debug!("From server: {:?}", msg);
-            let is_exit = match &msg {
-                Message::Notification(n) => n.method == "exit",
-                _ => false,
-            };
-
-            if sender.send(msg).is_err() {
-                return Err(Error::new(ErrorKind::Other, "Failed to send response"));
-            }
-
-            if is_exit {
-                break;
-            }
+        let Some(msg) = M::read(&mut reader)? else {
+            // The other side closed the stream.
+            return Ok(());
+        };
+        debug!("From server: {:?}", msg);
+        if sender.send(msg).is_err() {
+            return Err(Error::other("Failed to send response"));
         }
     }
 }
 
-fn writer_loop(mut writer: impl Write, receiver: &Receiver<Message>) -> io::Result<()> {
+pub fn writer_loop<M: TransportMessage>(
+    mut writer: impl Write,
+    receiver: &Receiver<M>,
+) -> io::Result<()> {
     for request in receiver {
         debug!("To server: {:?}", request);
         request.write(&mut writer)?;
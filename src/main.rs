@@ -3,21 +3,33 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::time::Instant;
 
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use color_eyre::eyre::Result;
-use crossbeam_channel::select;
+use crossbeam_channel::{tick, Receiver, Select};
 use lsp_server::{Connection, Message};
-use lsp_types::request::{Initialize, Request};
-use lsp_types::{ClientCapabilities, InitializeParams};
+use lsp_types::{ClientCapabilities, InitializeParams, MessageType};
 
+mod aggregation;
+mod build_discovery;
 mod build_env;
+mod build_watcher;
+mod column_mapping;
+mod configuration;
+mod dap;
 mod dispatch;
+mod doctor;
+mod filter_log;
 mod global_state;
 mod handler;
 mod language_server_transport;
+mod map;
+mod preprocess_worker;
+mod settings;
 mod source_mapping;
 mod thread_worker;
 mod websocket_logger;
@@ -25,21 +37,30 @@ mod websocket_logger;
 mod util;
 
 use crate::build_env::BuildEnv;
+use crate::build_watcher::BuildEvent;
+use crate::configuration::{Configuration, SourceMappings, PRIMARY};
 use crate::dispatch::{NotificationDispatcher, RequestDispatcher, ResponseDispatcher};
 use crate::global_state::{
     Direction::{FromServer, ToServer},
-    GlobalState, ReqContext,
+    GlobalState, ReqContext, ReqContextAlloc,
 };
 use crate::handler::*;
-use crate::source_mapping::MapDirection::ToPreprocess;
+use crate::preprocess_worker::PreprocessEvent;
+use crate::settings::Settings;
+use crate::source_mapping::MapDirection::{FromPreprocess, ToPreprocess};
 use crate::websocket_logger::Logger;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
-#[clap(group(ArgGroup::new("input").required(true).args(&["build_dir", "fiasco_dir"])))]
+#[clap(group(ArgGroup::new("input").args(&["build_dir", "fiasco_dir"])))]
+#[clap(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Build directory of a Fiasco configuration. Can be given multiple times, to serve several
+    /// configurations side by side.
     #[clap(long)]
-    build_dir: Option<PathBuf>,
+    build_dir: Vec<PathBuf>,
     #[clap(long, requires = "fiasco_config")]
     fiasco_dir: Option<PathBuf>,
     // TODO: Maybe allow also with build_dir?
@@ -47,15 +68,144 @@ struct Cli {
     fiasco_config: Option<PathBuf>,
     #[clap(long, requires = "fiasco_config")]
     makeconf: Option<PathBuf>,
+    /// Directory to search for build directories of the opened Fiasco source tree, besides the
+    /// source tree itself, if neither --build-dir nor --fiasco-dir is given. Can be given
+    /// multiple times.
+    #[clap(long, conflicts_with = "input")]
+    search_dir: Vec<PathBuf>,
     /// Connect to LSP editor on port.
     #[clap(long)]
     connect: Option<u16>,
     /// Listen for LSP editor on port.
     #[clap(long)]
     listen: Option<u16>,
-    // TODO: Make websocket logger configurable.
+    /// Path of the clangd executable, or of a wrapper that runs clangd.
+    #[clap(long)]
+    clangd: Option<String>,
+    /// Argument for clangd, e.g. `--clangd-arg=--background-index`. Can be given multiple times.
+    /// `${compileCommandsDir}` is replaced by the directory of the compilation database, without
+    /// it `--compile-commands-dir` is appended.
+    #[clap(long, allow_hyphen_values = true)]
+    clangd_arg: Vec<String>,
+    /// Environment variable for clangd as KEY=VALUE. Can be given multiple times.
+    #[clap(long, value_parser = parse_env)]
+    clangd_env: Vec<(String, String)>,
+    /// Port the websocket logger listens on.
+    #[clap(long)]
+    logger_port: Option<u16>,
     // TODO: Log client requests/answers to logger?!
-    // websocket_logger: Option<u16>,
+}
+
+fn parse_env(env: &str) -> Result<(String, String), String> {
+    match env.split_once('=') {
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+        None => Err(format!("expected KEY=VALUE, got {env}")),
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the line mappings of a build directory map every source line back to itself.
+    Doctor(DoctorArgs),
+    /// Map file:line[:col] locations between source and preprocessed files, or dump the mapping.
+    Map(MapArgs),
+    /// Rewrite the locations in build output read from stdin to the Fiasco sources.
+    FilterLog(FilterLogArgs),
+    /// Proxy the Debug Adapter Protocol between the client on stdin/stdout and a debug adapter,
+    /// e.g. `fiasco-lsp dap --build-dir <dir> -- gdb -i dap`.
+    Dap(DapArgs),
+}
+
+#[derive(Args)]
+struct DoctorArgs {
+    #[clap(long)]
+    build_dir: PathBuf,
+    /// Print the report as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+#[clap(group(ArgGroup::new("mode").required(true).args(&["to_preprocess", "from_preprocess", "dump"])))]
+struct MapArgs {
+    #[clap(long)]
+    build_dir: PathBuf,
+    /// Map source locations to preprocessed locations.
+    #[clap(long)]
+    to_preprocess: bool,
+    /// Map preprocessed locations to source locations.
+    #[clap(long)]
+    from_preprocess: bool,
+    /// Print the whole mapping table as JSON.
+    #[clap(long)]
+    dump: bool,
+    /// Locations as file:line[:col], one-based.
+    #[clap(required_unless_present = "dump", conflicts_with = "dump")]
+    locations: Vec<String>,
+}
+
+fn map(args: MapArgs) -> Result<()> {
+    let build_dir = args.build_dir.canonicalize()?;
+    let (source_mapping, _) = source_mapping::load_source_mapping(&build_dir);
+    if args.dump {
+        println!("{}", serde_json::to_string_pretty(&source_mapping.dump())?);
+        return Ok(());
+    }
+
+    let direction = if args.to_preprocess { ToPreprocess } else { FromPreprocess };
+    for location in &args.locations {
+        println!("{}", map::map_location(&source_mapping, direction, location)?);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct FilterLogArgs {
+    #[clap(long)]
+    build_dir: PathBuf,
+    #[clap(long, value_enum, default_value = "text")]
+    format: filter_log::LogFormat,
+}
+
+fn filter_log(args: FilterLogArgs) -> Result<()> {
+    let build_dir = args.build_dir.canonicalize()?;
+    let (source_mapping, _) = source_mapping::load_source_mapping(&build_dir);
+    filter_log::run(
+        &source_mapping,
+        &build_dir,
+        args.format,
+        std::io::stdin().lock(),
+        std::io::stdout().lock(),
+    )
+}
+
+#[derive(Args)]
+struct DapArgs {
+    #[clap(long)]
+    build_dir: PathBuf,
+    /// Command line of the debug adapter.
+    #[clap(last = true, required = true)]
+    adapter: Vec<String>,
+}
+
+fn dap(args: DapArgs) -> Result<()> {
+    let build_dir = args.build_dir.canonicalize()?;
+    let (source_mapping, _) = source_mapping::load_source_mapping(&build_dir);
+    dap::run(source_mapping, &args.adapter)
+}
+
+fn doctor(args: DoctorArgs) -> Result<()> {
+    let build_dir = args.build_dir.canonicalize()?;
+    let report = doctor::run(&build_dir);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.to_text());
+    }
+    if report.round_trip_errors() > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -63,26 +213,17 @@ fn main() -> Result<()> {
     env_logger::init();
 
     let cli: Cli = Cli::parse();
+    match cli.command {
+        Some(Command::Doctor(args)) => return doctor(args),
+        Some(Command::Map(args)) => return map(args),
+        Some(Command::FilterLog(args)) => return filter_log(args),
+        Some(Command::Dap(args)) => return dap(args),
+        None => {}
+    }
 
     // Note that  we must have our logging only write out to stderr.
     info!("Fiasco LSP Proxy");
 
-    let logger = Logger::spawn();
-
-    info!("Initialize build directory");
-    let build_env = match cli.build_dir {
-        Some(dir) => BuildEnv::from_dir(&dir),
-        None => BuildEnv::from_config(
-            &cli.fiasco_dir.unwrap(),
-            &cli.fiasco_config.unwrap(),
-            cli.makeconf.as_deref(),
-        ),
-    };
-    debug!("Build directory: {}", build_env.build_dir.to_string_lossy());
-
-    info!("Generate compilation database");
-    build_env.gen_compile_commands();
-
     // Create the transport. Includes the stdio (stdin and stdout) versions but this could
     // also be implemented to use sockets or HTTP.
     let (connection, io_threads) = if let Some(port) = cli.connect {
@@ -102,36 +243,180 @@ fn main() -> Result<()> {
     let (req_id, client_params) = connection.initialize_start()?;
     let client_capabilities: ClientCapabilities = serde_json::from_value(client_params.clone())?;
     debug!("Client capabilities: {:#?}", client_capabilities);
-    // TODO: Forward options to lsp...
-    let server = language_server_transport::start(
-        "clangd",
-        &["--compile-commands-dir", build_env.build_dir.to_str().unwrap()],
-    )?;
-    let initialize_request = lsp_server::Request {
-        id: req_id,
-        method: Initialize::METHOD.to_string(),
-        params: client_params,
+
+    let default_settings = Settings {
+        build_dir: cli.build_dir.first().cloned(),
+        fiasco_dir: cli.fiasco_dir,
+        fiasco_config: cli.fiasco_config,
+        makeconf: cli.makeconf,
+        clangd_path: cli.clangd,
+        clangd_args: Some(cli.clangd_arg).filter(|args| !args.is_empty()),
+        clangd_env: Some(cli.clangd_env.into_iter().collect())
+            .filter(|env: &BTreeMap<_, _>| !env.is_empty()),
+        logger_port: cli.logger_port,
+    };
+    let settings = match Settings::from_section(client_params.get("initializationOptions"))? {
+        Some(settings) => settings.or(&default_settings),
+        None => default_settings.clone(),
     };
+    debug!("Settings: {:?}", settings);
+    let logger = Logger::spawn(settings.logger_port());
 
-    server.to_lang_server.sender().send(Message::Request(initialize_request))?;
-    if let Message::Response(response) = server.from_lang_server.receiver().recv()? {
-        let initialization_params = response.result.unwrap();
-        debug!("Server capabilities: {:#?}", client_capabilities);
-        connection.initialize_finish(response.id, initialization_params.clone())?;
-        let state = GlobalState::new(
-            connection,
-            server,
-            logger,
-            source_mapping::load_source_mapping(&build_env.build_dir),
-        );
-        main_loop(state, initialization_params)?;
-        io_threads.join()?;
-
-        // Shut down gracefully.
-        info!("shutting down server");
-        Ok(())
-    } else {
-        panic!("Received invalid initialize response from server!")
+    info!("Initialize build directories");
+    // The primary configuration is the one of the settings, further configurations can only be
+    // given on the command line.
+    let mut build_envs = Vec::new();
+    match settings.build_env()? {
+        Some(build_env) => build_envs.push(build_env),
+        None => {
+            let params: InitializeParams = serde_json::from_value(client_params.clone())?;
+            let build_dir =
+                build_discovery::select_build_dir(&connection, &params, &cli.search_dir)?;
+            info!("Discovered build directory {}", build_dir.display());
+            build_envs.push(BuildEnv::from_dir(&build_dir));
+        }
+    }
+    build_envs.extend(cli.build_dir.iter().skip(1).map(|dir| BuildEnv::from_dir(dir)));
+    for build_env in &build_envs {
+        debug!("Build directory: {}", build_env.build_dir.to_string_lossy());
+        info!("Generate compilation database");
+        build_env.gen_compile_commands()?;
+    }
+
+    // Start one language server per configuration. The client gets the capabilities of the
+    // primary configuration's server, the servers are the same anyway.
+    let mut configs = Vec::new();
+    let mut initialize_result = None;
+    for build_env in build_envs {
+        let (config, result) = Configuration::start(build_env, &settings, &client_params)?;
+        initialize_result.get_or_insert(result);
+        configs.push(config);
+    }
+
+    let mut initialization_params = initialize_result.unwrap();
+    debug!("Server capabilities: {:#?}", client_capabilities);
+    execute_command::add_commands(&mut initialization_params);
+    connection.initialize_finish(req_id, initialization_params.clone())?;
+    let mut source_mapping = SourceMappings::new();
+    let mut reports = Vec::new();
+    for config in &configs {
+        let build_dir = &config.build_env.build_dir;
+        let (mapping, report) = source_mapping::load_source_mapping(build_dir);
+        source_mapping.push(build_dir, mapping);
+        if !report.is_empty() {
+            reports.push(match configs.len() {
+                1 => report.summary(),
+                _ => format!("{}: {}", config.name, report.summary()),
+            });
+        }
+    }
+    let mut state = GlobalState::new(
+        connection,
+        logger,
+        client_params,
+        default_settings,
+        settings,
+        configs,
+        source_mapping,
+    );
+    for report in reports {
+        state.show_message(MessageType::WARNING, report);
+    }
+    main_loop(state, initialization_params)?;
+    io_threads.join()?;
+
+    // Shut down gracefully.
+    info!("shutting down server");
+    Ok(())
+}
+
+enum Event {
+    Client(Message),
+    /// Message from the language server of a configuration.
+    Server(usize, Message),
+    /// The language server of a configuration exited.
+    ServerExit(usize),
+    /// Line the language server of a configuration wrote to stderr.
+    ServerLog(usize, String),
+    /// Change in the build directory of a configuration.
+    Build(usize, BuildEvent),
+    /// Time to check for hanging language servers, overdue responses and expired requests.
+    Watchdog,
+    /// Time to preprocess the unsaved content of changed source files.
+    PreprocessOverlay,
+    /// A preprocess run on the worker thread finished.
+    Preprocessed(PreprocessEvent),
+}
+
+/// Waits for the next message from the client, any of the running language servers (or their
+/// stderr), build directory watchers or the preprocess worker, or for the next pending preprocess
+/// of unsaved content.
+fn next_event(state: &GlobalState, watchdog: &Receiver<Instant>) -> Event {
+    let servers: Vec<_> = state
+        .configs
+        .iter()
+        .enumerate()
+        .filter(|(_, config)| config.running)
+        .map(|(index, config)| (index, &config.server))
+        .collect();
+    let watchers: Vec<_> =
+        state.configs.iter().map(|config| config.build_watcher.receiver()).collect();
+
+    let mut select = Select::new();
+    select.recv(&state.client.receiver);
+    select.recv(watchdog);
+    for (_, server) in &servers {
+        select.recv(server.from_lang_server.receiver());
+    }
+    for (_, server) in &servers {
+        select.recv(server.stderr.receiver());
+    }
+    for receiver in &watchers {
+        select.recv(receiver);
+    }
+    select.recv(state.preprocess_worker.receiver());
+    // Source files are preprocessed again only after the running preprocess finished.
+    let deadline = state
+        .pending_overlays
+        .iter()
+        .filter(|(source, _)| !state.overlay_jobs.contains_key(*source))
+        .map(|(_, deadline)| deadline)
+        .min();
+    let op = match deadline {
+        Some(deadline) => match select.select_deadline(*deadline) {
+            Ok(op) => op,
+            Err(_) => return Event::PreprocessOverlay,
+        },
+        None => select.select(),
+    };
+    match op.index() {
+        0 => Event::Client(op.recv(&state.client.receiver).expect("Lost connection to client!")),
+        1 => {
+            op.recv(watchdog).expect("Lost watchdog timer!");
+            Event::Watchdog
+        }
+        index if index < 2 + servers.len() => {
+            let (config, server) = servers[index - 2];
+            match op.recv(server.from_lang_server.receiver()) {
+                Ok(msg) => Event::Server(config, msg),
+                Err(_) => Event::ServerExit(config),
+            }
+        }
+        index if index < 2 + 2 * servers.len() => {
+            let (config, server) = servers[index - 2 - servers.len()];
+            let line = op.recv(server.stderr.receiver()).expect("Lost language server stderr!");
+            Event::ServerLog(config, line)
+        }
+        index if index < 2 + 2 * servers.len() + watchers.len() => {
+            let config = index - 2 - 2 * servers.len();
+            let event = op.recv(watchers[config]).expect("Lost build directory watcher!");
+            Event::Build(config, event)
+        }
+        _ => {
+            let event =
+                op.recv(state.preprocess_worker.receiver()).expect("Lost preprocess worker!");
+            Event::Preprocessed(event)
+        }
     }
 }
 
@@ -139,41 +424,43 @@ fn main_loop(mut state: GlobalState, params: serde_json::Value) -> Result<()> {
     let _params: InitializeParams = serde_json::from_value(params).unwrap();
     info!("starting example main loop");
 
+    let watchdog = tick(recovery::WATCHDOG_INTERVAL);
     loop {
-        select! {
-            recv(state.client.receiver) -> r => {
-                let msg = r.expect("Lost connection to client!");
-                match msg.clone() {
-                    Message::Request(req) => {
-                        if state.client.handle_shutdown(&req)? {
-                            return Ok(());
-                        }
-
-                        state.handle_client_request(req)
-                    }
-                    Message::Response(res) => {
-                        state.handle_client_response(res)
-                    }
-                    Message::Notification(not) => {
-                        state.handle_client_notification(not)
+        match next_event(&state, &watchdog) {
+            Event::Client(msg) => match msg {
+                Message::Request(req) => {
+                    if state.client.handle_shutdown(&req)? {
+                        return Ok(());
                     }
+
+                    state.handle_client_request(req)
                 }
+                Message::Response(res) => state.handle_client_response(res),
+                Message::Notification(not) => state.handle_client_notification(not),
             },
-            recv(state.server.from_lang_server.receiver()) -> r => {
-                let msg = r.expect("Lost connection to server!");
+            Event::Server(config, msg) => {
+                state.configs[config].last_message = Instant::now();
                 state.log_from_server(&msg)?;
-                match msg.clone() {
-                    Message::Request(req) => {
-                        state.handle_server_request(req)
-                    }
-                    Message::Response(res) => {
-                        state.handle_server_response(res)
-                    }
-                    Message::Notification(not) => {
-                        state.handle_server_notification(not)
-                    }
+                match msg {
+                    Message::Request(req) => state.handle_server_request(config, req),
+                    Message::Response(res) => state.handle_server_response(res),
+                    Message::Notification(not) => state.handle_server_notification(config, not),
                 }
-            },
+            }
+            Event::ServerExit(config) => recovery::handle_server_exit(&mut state, config),
+            Event::ServerLog(config, line) => {
+                server_log::handle_server_stderr(&mut state, config, line)
+            }
+            Event::Build(config, event) => {
+                document_sync::handle_build_event(&mut state, config, event)
+            }
+            Event::Watchdog => {
+                recovery::handle_watchdog(&mut state);
+                cancel::handle_overdue_aggregations(&mut state);
+                pending_requests::handle_expired_requests(&mut state);
+            }
+            Event::PreprocessOverlay => document_sync::handle_pending_overlays(&mut state),
+            Event::Preprocessed(event) => document_sync::handle_preprocess_event(&mut state, event),
         }
     }
 }
@@ -181,9 +468,8 @@ fn main_loop(mut state: GlobalState, params: serde_json::Value) -> Result<()> {
 impl GlobalState {
     fn handle_client_notification(&mut self, not: lsp_server::Notification) {
         use lsp_types::notification::*;
-        NotificationDispatcher { direction: ToServer, not: Some(not), state: self }
-            // TODO: Do we need to update something in our state? Maybe mark the request as cancelled? Server nevertheless must send a reply!
-            .forward::<Cancel>()
+        NotificationDispatcher { direction: ToServer, not: Some(not), config: PRIMARY, state: self }
+            .on_local::<Cancel>(cancel::handle_cancel)
             // TODO: Adjust our verbosity?
             .forward::<SetTrace>()
             // TODO: Return some log?
@@ -196,10 +482,9 @@ impl GlobalState {
             .on_many::<DidChangeTextDocument>(document_sync::handle_did_change_text_document)
             // TODO: Map TextDocumentIdentifier
             .forward::<WillSaveTextDocument>()
-            // TODO: Map TextDocumentIdentifier and text context
-            .forward::<DidSaveTextDocument>()
+            .on_many::<DidSaveTextDocument>(document_sync::handle_did_save_text_document)
             .on_many::<DidCloseTextDocument>(document_sync::handle_did_close_text_document)
-            .forward::<DidChangeConfiguration>()
+            .on::<DidChangeConfiguration>(change_configuration::handle_did_change_configuration)
             // TODO: Translate FileEvent
             .forward::<DidChangeWatchedFiles>()
             // TODO: Find out what needs to be done.
@@ -213,9 +498,10 @@ impl GlobalState {
             .finish()
     }
 
-    fn handle_server_notification(&mut self, not: lsp_server::Notification) {
+    fn handle_server_notification(&mut self, config: usize, not: lsp_server::Notification) {
         use lsp_types::notification::*;
-        NotificationDispatcher { direction: FromServer, not: Some(not), state: self }
+        NotificationDispatcher { direction: FromServer, not: Some(not), config, state: self }
+            .on_local::<Cancel>(cancel::handle_cancel)
             .forward::<ShowMessage>()
             .forward::<LogMessage>()
             .forward::<TelemetryEvent>()
@@ -226,7 +512,7 @@ impl GlobalState {
 
     fn handle_client_request(&mut self, req: lsp_server::Request) {
         use lsp_types::request::*;
-        RequestDispatcher { direction: ToServer, req: Some(req), state: self }
+        RequestDispatcher { direction: ToServer, req: Some(req), config: PRIMARY, state: self }
             .forward::<Initialize>()
             .forward::<Shutdown>()
             .forward::<RegisterCapability>()
@@ -234,27 +520,40 @@ impl GlobalState {
             .forward::<WorkspaceSymbolRequest>()
             // TODO: Location / WorkspaceLocation must be mapped
             .forward::<WorkspaceSymbolResolve>()
+            .on_local::<ExecuteCommand>(execute_command::handle_execute_command)
+            .on_local::<pending_requests::PendingRequests>(
+                pending_requests::handle_pending_requests,
+            )
             .forward::<ExecuteCommand>()
             // TODO: Might map to multiple files...
             .forward::<WillSaveWaitUntil>()
             .on::<Completion>(handle_source_location!(text_document_position))
-            // TODO: TextEdit must be translated
-            .forward::<ResolveCompletionItem>()
-            .on::<HoverRequest>(handle_source_location!(text_document_position_params))
+            .on::<ResolveCompletionItem>(completion::handle_req_resolve_completion)
+            .on_many::<HoverRequest>(handle_source_location_many!(
+                text_document_position_params,
+                HoverRequest
+            ))
             .on::<SignatureHelpRequest>(handle_source_location!(text_document_position_params))
-            .on::<GotoDeclaration>(handle_source_location!(text_document_position_params))
-            .on::<GotoDefinition>(handle_source_location!(text_document_position_params))
-            .on::<References>(handle_source_location!(text_document_position))
-            // TODO: Might need many here, in case one location is mapped to multiple files (function decl e.g.)
-            .on::<DocumentHighlightRequest>(handle_source_location!(text_document_position_params))
-            .on_many::<DocumentSymbolRequest>(document_symbol::handle_req_doc_symbol)
+            .on_many::<GotoDeclaration>(handle_source_location_many!(
+                text_document_position_params,
+                GotoDeclaration
+            ))
+            .on_many::<GotoDefinition>(handle_source_location_many!(
+                text_document_position_params,
+                GotoDefinition
+            ))
+            .on_many::<References>(handle_source_location_many!(text_document_position, References))
+            .on_many::<DocumentHighlightRequest>(handle_source_location_many!(
+                text_document_position_params,
+                DocumentHighlightRequest
+            ))
+            .on_split::<DocumentSymbolRequest>()
             .on::<CodeActionRequest>(code_action::handle_req_code_action)
             // TODO: TextDocumentIdentifier must be mapped
             .forward::<CodeLensRequest>()
             // TODO: Range must be mapped, maybe use the data value as identifier?!
             .forward::<CodeLensResolve>()
-            // TODO: TextDocumentIdentifier must be mapped
-            .forward::<DocumentLinkRequest>()
+            .on_split::<DocumentLinkRequest>()
             // TODO: DocumentLink must be mapped
             .forward::<DocumentLinkResolve>()
             // TODO: TextDocumentIdentifier and Range must be mapped
@@ -263,17 +562,21 @@ impl GlobalState {
             // TODO: TextDocumentIdentifier must be mapped
             .forward::<Formatting>()
             .on::<Rename>(handle_source_location!(text_document_position))
-            // TODO: TextDocumentIdentifier must be mapped
-            .forward::<DocumentColor>()
+            .on_split::<DocumentColor>()
             // TODO: TextDocumentIdentifier and Range must be mapped
             .forward::<ColorPresentationRequest>()
-            // TODO: TextDocumentIdentifier must be mapped
-            .forward::<FoldingRangeRequest>()
+            .on_split::<FoldingRangeRequest>()
             // TODO: TextDocumentIdentifier and Position must be mapped
             .forward::<PrepareRenameRequest>()
             // TODO: Unify all users of GotoDefinition
-            .on::<GotoImplementation>(handle_source_location!(text_document_position_params))
-            .on::<GotoTypeDefinition>(handle_source_location!(text_document_position_params))
+            .on_many::<GotoImplementation>(handle_source_location_many!(
+                text_document_position_params,
+                GotoImplementation
+            ))
+            .on_many::<GotoTypeDefinition>(handle_source_location_many!(
+                text_document_position_params,
+                GotoTypeDefinition
+            ))
             // TODO: TextDocumentIdentifier and Position must be mapped
             .forward::<SelectionRangeRequest>()
             // TODO: Url and Range and SelectionRange need to be mapped
@@ -297,8 +600,7 @@ impl GlobalState {
             .forward::<WillDeleteFiles>()
             // TODO: Diagnostic and more must be mapped
             .forward::<CodeActionResolveRequest>()
-            // TODO: TextDocumentIdentifier and Range must be resolved (can span entire file, so we might have to map this to multiple requests...).
-            .on_many::<InlayHintRequest>(inlay_hint::handle_req_inlay_hint)
+            .on_split::<InlayHintRequest>()
             // TODO: Position and Locationmust be resolved.
             .forward::<InlayHintResolveRequest>()
             // TODO: TextDocumentIdentifier and Range must be resolved.
@@ -315,9 +617,9 @@ impl GlobalState {
             .finish()
     }
 
-    fn handle_server_request(&mut self, req: lsp_server::Request) {
+    fn handle_server_request(&mut self, config: usize, req: lsp_server::Request) {
         use lsp_types::request::*;
-        RequestDispatcher { direction: FromServer, req: Some(req), state: self }
+        RequestDispatcher { direction: FromServer, req: Some(req), config, state: self }
             .forward::<ShowMessageRequest>()
             // TODO: WorkspaceEdit must be mapped
             .forward::<ApplyWorkspaceEdit>()
@@ -368,28 +670,24 @@ impl GlobalState {
             .forward::<ExecuteCommand>()
             // TODO: TextEdit need to be mapped
             .forward::<WillSaveWaitUntil>()
-            // TODO: All the TextEduts must be mapped.
-            .forward::<Completion>()
-            // TODO: TextEdit need to be mapped
-            .forward::<ResolveCompletionItem>()
-            // TODO: Range must be translated
-            .forward::<HoverRequest>()
+            .on::<Completion>(completion::handle_res_completion)
+            .on::<ResolveCompletionItem>(completion::handle_res_resolve_completion)
+            .on_collect::<HoverRequest>(hover::handle_res_hover, hover::merge_hover)
             .forward::<SignatureHelpRequest>()
-            // TODO: LocationLink must be mapped (and Location mapping is wrong, uses self.mapped_file which is wrong)
-            .on::<GotoDeclaration>(goto::handle_res_goto)
-            // TODO: LocationLink must be mapped (and Location mapping is wrong, uses self.mapped_file which is wrong)
-            .on::<GotoDefinition>(goto::handle_res_goto)
-            .on::<References>(goto::handle_res_references)
-            // TODO: Range must be mapped
-            .on::<DocumentHighlightRequest>(document_highlight::handle_res_document_highlight)
-            .on_collect::<DocumentSymbolRequest>(document_symbol::handle_res_doc_symbol)
+            .on_collect::<GotoDeclaration>(goto::handle_res_goto, goto::merge_goto)
+            .on_collect::<GotoDefinition>(goto::handle_res_goto, goto::merge_goto)
+            .on_collect::<References>(goto::handle_res_references, goto::merge_references)
+            .on_collect::<DocumentHighlightRequest>(
+                document_highlight::handle_res_document_highlight,
+                document_highlight::merge_document_highlights,
+            )
+            .on_split::<DocumentSymbolRequest>()
             .on::<CodeActionRequest>(code_action::handle_res_code_action)
             // TODO: Range must be mapped
             .forward::<CodeLensRequest>()
             // TODO: Range must be mapped
             .forward::<CodeLensResolve>()
-            // TODO: DocumentLink must be mapped, we might need to filter results to include stuff for current document.
-            .forward::<DocumentLinkRequest>()
+            .on_split::<DocumentLinkRequest>()
             // TODO: DocumentLink must be mapped
             .forward::<DocumentLinkResolve>()
             // TODO: TextEdit must be mapped
@@ -400,18 +698,14 @@ impl GlobalState {
             .forward::<Formatting>()
             // TODO: TextEdit must be mapped
             .forward::<Rename>()
-            // TODO: Range must be mapped, we might need to filter results to include stuff for current document.
-            .forward::<DocumentColor>()
+            .on_split::<DocumentColor>()
             // TODO: TextEdit must be mapped
             .forward::<ColorPresentationRequest>()
-            // TODO: FoldingRange must be mapped
-            .forward::<FoldingRangeRequest>()
+            .on_split::<FoldingRangeRequest>()
             // TODO: Range must be mapped
             .forward::<PrepareRenameRequest>()
-            // TODO: LocationLink must be mapped (and Location mapping is wrong, uses self.mapped_file which is wrong)
-            .on::<GotoImplementation>(goto::handle_res_goto)
-            // TODO: LocationLink must be mapped (and Location mapping is wrong, uses self.mapped_file which is wrong)
-            .on::<GotoTypeDefinition>(goto::handle_res_goto)
+            .on_collect::<GotoImplementation>(goto::handle_res_goto, goto::merge_goto)
+            .on_collect::<GotoTypeDefinition>(goto::handle_res_goto, goto::merge_goto)
             // TODO: Range must be mapped
             .forward::<SelectionRangeRequest>()
             // TODO: Url and Range and SelectionRange need to be mapped
@@ -437,8 +731,7 @@ impl GlobalState {
             .forward::<WillDeleteFiles>()
             // TODO: Diagnostic and more must be mapped
             .forward::<CodeActionResolveRequest>()
-            // TODO: Position and Location must be resolved (might need to filter to include stuff for current document).
-            .on_collect::<InlayHintRequest>(inlay_hint::handle_res_inlay_hint)
+            .on_split::<InlayHintRequest>()
             // TODO: Position and Location must be resolved.
             .forward::<InlayHintResolveRequest>()
             // TODO: Range must be resolved.
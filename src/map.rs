@@ -0,0 +1,89 @@
+//! Translates positions between source and preprocessed files from the command line, without a
+//! language server.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result};
+
+use crate::source_mapping::{FiascoSourceMapping, MapDirection, SourceLocation};
+
+/// Parses a `file:line[:col]` location with one-based line and column, as printed by compilers.
+pub fn parse_location(location: &str) -> Result<SourceLocation> {
+    let invalid = || eyre!("Invalid location {location:?}, expected file:line[:col].");
+    let number = |s: &str| s.parse::<u32>().ok().and_then(|n| n.checked_sub(1));
+    let mut parts = location.rsplitn(3, ':');
+    let last = parts.next().ok_or_else(invalid)?;
+    let middle = parts.next().ok_or_else(invalid)?;
+    match parts.next() {
+        Some(path) if middle.parse::<u32>().is_ok() => {
+            let line = number(middle).ok_or_else(invalid)?;
+            let character = number(last).ok_or_else(invalid)?;
+            Ok(SourceLocation { path: PathBuf::from(path), line, character })
+        }
+        // The column is optional, the "line" might then be part of the path.
+        _ => {
+            let line = number(last).ok_or_else(invalid)?;
+            let path = location.rsplit_once(':').ok_or_else(invalid)?.0;
+            Ok(SourceLocation { path: PathBuf::from(path), line, character: 0 })
+        }
+    }
+}
+
+/// Resolves a path given on the command line to the path used by the mapping, which might differ
+/// e.g. by being relative or going through the `source` symlink of the build directory.
+pub fn resolve_path(
+    source_mapping: &FiascoSourceMapping,
+    direction: MapDirection,
+    path: &Path,
+) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    if source_mapping.files(direction).any(|file| file == absolute) {
+        return absolute;
+    }
+    let Ok(canonical) = absolute.canonicalize() else {
+        return absolute;
+    };
+    source_mapping
+        .files(direction)
+        .find(|file| file.canonicalize().is_ok_and(|file| file == canonical))
+        .map_or(absolute, Path::to_path_buf)
+}
+
+/// Maps a location given on the command line and formats the result as `file:line:col`.
+pub fn map_location(
+    source_mapping: &FiascoSourceMapping,
+    direction: MapDirection,
+    location: &str,
+) -> Result<String> {
+    let location = parse_location(location)?;
+    let path = resolve_path(source_mapping, direction, &location.path);
+    let mapped =
+        source_mapping.map(direction, path.to_str().unwrap(), location.line, location.character);
+    Ok(format!("{}:{}:{}", mapped.path.display(), mapped.line + 1, mapped.character + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let location = parse_location("auto/foo.cpp:12:3").unwrap();
+        assert_eq!(
+            location,
+            SourceLocation { path: "auto/foo.cpp".into(), line: 11, character: 2 }
+        );
+        let location = parse_location("auto/foo.cpp:12").unwrap();
+        assert_eq!(
+            location,
+            SourceLocation { path: "auto/foo.cpp".into(), line: 11, character: 0 }
+        );
+        assert!(parse_location("auto/foo.cpp").is_err());
+        assert!(parse_location("auto/foo.cpp:0").is_err());
+        // Line and column are one-based.
+        assert!(parse_location("auto/foo.cpp:0:5").is_err());
+        assert!(parse_location("auto/foo.cpp:5:0").is_err());
+        let location = parse_location("c:/foo.cpp:12").unwrap();
+        assert_eq!(location, SourceLocation { path: "c:/foo.cpp".into(), line: 11, character: 0 });
+    }
+}
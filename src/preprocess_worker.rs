@@ -0,0 +1,85 @@
+//! Runs preprocess on a worker thread, so that the main loop keeps serving the client and the
+//! language servers while `make` runs. The results are handled by the main loop, see
+//! `handle_preprocess_event`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use color_eyre::eyre::Result;
+use crossbeam_channel::Receiver;
+
+use crate::build_env::Preprocessor;
+use crate::thread_worker::Worker;
+
+#[derive(Debug)]
+pub enum PreprocessJob {
+    /// Re-run preprocess for the modules of a saved source file, in the build directory of a
+    /// configuration.
+    Modules { config: usize, source: PathBuf, preprocessor: Preprocessor, modules: Vec<String> },
+    /// Preprocess the modules of a changed source file, with the given unsaved content of the
+    /// opened source files.
+    Overlay {
+        config: usize,
+        source: PathBuf,
+        preprocessor: Preprocessor,
+        modules: Vec<String>,
+        sources: HashMap<PathBuf, String>,
+    },
+}
+
+#[derive(Debug)]
+pub enum PreprocessEvent {
+    /// Preprocess for the modules of a saved source file finished.
+    Modules { config: usize, source: PathBuf, build_dir: PathBuf, result: Result<Vec<String>> },
+    /// Preprocess of the unsaved content of a changed source file finished, with the generated
+    /// files and their content.
+    Overlay {
+        config: usize,
+        source: PathBuf,
+        build_dir: PathBuf,
+        result: Result<Vec<(PathBuf, String)>>,
+    },
+}
+
+impl PreprocessJob {
+    fn run(self) -> PreprocessEvent {
+        match self {
+            PreprocessJob::Modules { config, source, preprocessor, modules } => {
+                let result = preprocessor.preprocess(&modules).map(|()| modules);
+                PreprocessEvent::Modules {
+                    config,
+                    source,
+                    build_dir: preprocessor.build_dir,
+                    result,
+                }
+            }
+            PreprocessJob::Overlay { config, source, preprocessor, modules, sources } => {
+                let mut generated = Vec::new();
+                let result = modules
+                    .iter()
+                    .try_for_each(|module| {
+                        generated.extend(preprocessor.preprocess_overlay(module, &sources)?);
+                        Ok(())
+                    })
+                    .map(|()| generated);
+                PreprocessEvent::Overlay {
+                    config,
+                    source,
+                    build_dir: preprocessor.build_dir,
+                    result,
+                }
+            }
+        }
+    }
+}
+
+/// Spawns the worker, which runs the jobs one after the other, in the order they were sent.
+pub fn spawn() -> Worker<PreprocessJob, PreprocessEvent> {
+    Worker::spawn("Preprocess worker", 16, |receiver: Receiver<PreprocessJob>, sender| {
+        for job in receiver {
+            if sender.send(job.run()).is_err() {
+                break;
+            }
+        }
+    })
+}
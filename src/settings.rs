@@ -0,0 +1,201 @@
+//! Settings of the proxy that the client can change, given as `fiasco` section of the
+//! `initializationOptions` of `initialize` and of the settings of
+//! `workspace/didChangeConfiguration`. Settings that are not given fall back to the command line.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::build_env::BuildEnv;
+use crate::websocket_logger;
+
+/// Name of the section with the settings of the proxy.
+pub const SECTION: &str = "fiasco";
+
+/// Placeholder in the clangd arguments for the directory of the compilation database.
+pub const COMPILE_COMMANDS_DIR: &str = "${compileCommandsDir}";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// Build directory of the primary configuration.
+    pub build_dir: Option<PathBuf>,
+    /// Fiasco source directory, to create the build directory of the primary configuration from
+    /// `fiasco_config`, instead of using `build_dir`.
+    pub fiasco_dir: Option<PathBuf>,
+    /// Fiasco `globalconfig.out`.
+    pub fiasco_config: Option<PathBuf>,
+    /// `Makeconf.local` for the build directory created from `fiasco_config`.
+    pub makeconf: Option<PathBuf>,
+    /// Path of the clangd executable, or of a wrapper that runs clangd.
+    pub clangd_path: Option<String>,
+    /// Arguments for clangd, `${compileCommandsDir}` is replaced by the directory of the
+    /// compilation database. Without it, `--compile-commands-dir` is appended.
+    pub clangd_args: Option<Vec<String>>,
+    /// Additional environment variables for clangd.
+    pub clangd_env: Option<BTreeMap<String, String>>,
+    /// Port the websocket logger listens on.
+    pub logger_port: Option<u16>,
+}
+
+impl Settings {
+    /// Reads the settings from the `fiasco` section of the given object, e.g. the
+    /// `initializationOptions`. Returns `None` if there is no such section.
+    pub fn from_section(value: Option<&Value>) -> Result<Option<Settings>> {
+        match value.and_then(|value| value.get(SECTION)) {
+            None | Some(Value::Null) => Ok(None),
+            Some(section) => serde_json::from_value(section.clone())
+                .map(Some)
+                .wrap_err_with(|| format!("Invalid {SECTION} settings")),
+        }
+    }
+
+    /// Returns these settings with the unset ones taken from `defaults`. The build directory
+    /// settings are taken as a whole, so that e.g. a build directory given by the client
+    /// overrides a config file given on the command line.
+    pub fn or(self, defaults: &Settings) -> Settings {
+        let build = match self.build_dir.is_some() || self.fiasco_dir.is_some() {
+            true => (self.build_dir, self.fiasco_dir, self.fiasco_config, self.makeconf),
+            false => (
+                defaults.build_dir.clone(),
+                defaults.fiasco_dir.clone(),
+                defaults.fiasco_config.clone(),
+                defaults.makeconf.clone(),
+            ),
+        };
+        let (build_dir, fiasco_dir, fiasco_config, makeconf) = build;
+        Settings {
+            build_dir,
+            fiasco_dir,
+            fiasco_config,
+            makeconf,
+            clangd_path: self.clangd_path.or_else(|| defaults.clangd_path.clone()),
+            clangd_args: self.clangd_args.or_else(|| defaults.clangd_args.clone()),
+            clangd_env: self.clangd_env.or_else(|| defaults.clangd_env.clone()),
+            logger_port: self.logger_port.or(defaults.logger_port),
+        }
+    }
+
+    /// Returns whether the build directory of the primary configuration differs.
+    pub fn build_changed(&self, other: &Settings) -> bool {
+        (&self.build_dir, &self.fiasco_dir, &self.fiasco_config, &self.makeconf)
+            != (&other.build_dir, &other.fiasco_dir, &other.fiasco_config, &other.makeconf)
+    }
+
+    /// Returns whether the language server must be restarted.
+    pub fn server_changed(&self, other: &Settings) -> bool {
+        (&self.clangd_path, &self.clangd_args, &self.clangd_env)
+            != (&other.clangd_path, &other.clangd_args, &other.clangd_env)
+    }
+
+    pub fn clangd_path(&self) -> &str {
+        self.clangd_path.as_deref().unwrap_or("clangd")
+    }
+
+    /// Returns the arguments for clangd with the directory of the compilation database
+    /// substituted. Fails if the directory is not valid UTF-8.
+    pub fn clangd_args(&self, compile_commands_dir: &Path) -> Result<Vec<String>> {
+        let dir = compile_commands_dir.to_str().ok_or_else(|| {
+            eyre!("Invalid compile commands dir {}, not UTF-8.", compile_commands_dir.display())
+        })?;
+        let args = self.clangd_args.as_deref().unwrap_or_default();
+        let mut substituted: Vec<String> =
+            args.iter().map(|arg| arg.replace(COMPILE_COMMANDS_DIR, dir)).collect();
+        if !args.iter().any(|arg| arg.contains(COMPILE_COMMANDS_DIR)) {
+            substituted.extend(["--compile-commands-dir".to_owned(), dir.to_owned()]);
+        }
+        Ok(substituted)
+    }
+
+    pub fn clangd_env(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.clangd_env.iter().flatten()
+    }
+
+    pub fn logger_port(&self) -> u16 {
+        self.logger_port.unwrap_or(websocket_logger::DEFAULT_PORT)
+    }
+
+    /// Creates the build environment of the primary configuration, returns `None` if neither a
+    /// build directory nor a Fiasco config is set.
+    pub fn build_env(&self) -> Result<Option<BuildEnv>> {
+        match (&self.fiasco_dir, &self.fiasco_config, &self.build_dir) {
+            (Some(fiasco_dir), Some(config), _) => {
+                if !fiasco_dir.is_dir() {
+                    return Err(eyre!("Invalid Fiasco directory {}.", fiasco_dir.display()));
+                }
+                if !config.is_file() {
+                    return Err(eyre!("Invalid Fiasco config {}.", config.display()));
+                }
+                Ok(Some(BuildEnv::from_config(fiasco_dir, config, self.makeconf.as_deref())))
+            }
+            (Some(_), None, _) | (None, Some(_), _) => {
+                Err(eyre!("fiascoDir and fiascoConfig must be given together."))
+            }
+            (None, None, Some(build_dir)) => {
+                if !build_dir.is_dir() {
+                    return Err(eyre!("Invalid build directory {}.", build_dir.display()));
+                }
+                Ok(Some(BuildEnv::from_dir(build_dir)))
+            }
+            (None, None, None) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn settings_or_defaults() {
+        let defaults = Settings {
+            fiasco_dir: Some("/fiasco".into()),
+            fiasco_config: Some("/fiasco/globalconfig.out".into()),
+            logger_port: Some(1234),
+            ..Settings::default()
+        };
+        let options = json!({"fiasco": {"buildDir": "/build", "clangdArgs": ["--log=verbose"]}});
+        let settings = Settings::from_section(Some(&options)).unwrap().unwrap().or(&defaults);
+        assert_eq!(
+            settings,
+            Settings {
+                build_dir: Some("/build".into()),
+                clangd_args: Some(vec!["--log=verbose".to_owned()]),
+                logger_port: Some(1234),
+                ..Settings::default()
+            }
+        );
+        assert!(settings.build_changed(&defaults));
+        assert!(settings.server_changed(&defaults));
+        assert_eq!(settings.clangd_path(), "clangd");
+        assert_eq!(
+            settings.clangd_args(Path::new("/build")).unwrap(),
+            ["--log=verbose", "--compile-commands-dir", "/build"]
+        );
+
+        let options = json!({"fiasco": {
+            "clangdPath": "docker",
+            "clangdArgs": ["exec", "-i", "fiasco", "clangd", "--compile-commands-dir=${compileCommandsDir}"],
+            "clangdEnv": {"DOCKER_HOST": "unix:///run/docker.sock"},
+        }});
+        let settings = Settings::from_section(Some(&options)).unwrap().unwrap();
+        assert_eq!(
+            settings.clangd_args(Path::new("/build")).unwrap(),
+            ["exec", "-i", "fiasco", "clangd", "--compile-commands-dir=/build"]
+        );
+        assert_eq!(settings.clangd_env().count(), 1);
+        let dir = Path::new(OsStr::from_bytes(b"/build\xe4"));
+        assert!(settings.clangd_args(dir).is_err());
+
+        assert_eq!(Settings::from_section(Some(&json!({"clangd": {}}))).unwrap(), None);
+        assert!(Settings::from_section(Some(&json!({"fiasco": {"loggerPort": "x"}}))).is_err());
+    }
+}
@@ -1,31 +1,62 @@
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::{fs, io};
 
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+use crate::column_mapping::{diff_columns, map_column, ColumnSegment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PreprocessSection {
     None,
     Interface,
     Implementation,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LineMapping {
     section: PreprocessSection,
     src_line: u32,
     src_end_line: u32, // Exclusive
     dst_file: PathBuf,
     dst_line: u32,
+    /// Column segments of the lines whose content was changed by preprocess, indexed by the line
+    /// offset within the mapping. Columns of all other lines are mapped unchanged.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    columns: BTreeMap<u32, Vec<ColumnSegment>>,
+}
+
+/// Translates a line number of a file in which the lines `start..=end` were replaced by the lines
+/// `start..=new_end`.
+fn shift_line(line: u32, start: u32, end: u32, new_end: u32) -> u32 {
+    if line <= start {
+        line
+    } else if line > end {
+        line - end + new_end
+    } else {
+        // Line was inside the replaced lines.
+        line.min(new_end)
+    }
+}
+
+/// Like `shift_line`, for the last line of a mapping. Lines inserted into that line belong to the
+/// mapping, e.g. when the line is split.
+fn shift_end_line(line: u32, start: u32, end: u32, new_end: u32) -> u32 {
+    if line == start && start == end {
+        new_end
+    } else {
+        shift_line(line, start, end, new_end)
+    }
 }
 
 impl LineMapping {
@@ -37,6 +68,29 @@ impl LineMapping {
         // x1 <= y2 && y1 <= x2
         start <= self.src_end_line && self.src_line <= end
     }
+
+    fn map_column(&self, line: u32, character: u32) -> u32 {
+        match self.columns.get(&(line - self.src_line)) {
+            Some(segments) => map_column(segments, character),
+            None => character,
+        }
+    }
+
+    /// Moves the column segments along with their lines, for a mapping that starts at line `base`
+    /// in the edited file. Segments of edited lines are dropped, as they are outdated.
+    fn shift_columns(&mut self, base: u32, new_base: u32, start: u32, end: u32, new_end: u32) {
+        self.columns = std::mem::take(&mut self.columns)
+            .into_iter()
+            .filter_map(|(offset, segments)| {
+                let line = base + offset;
+                if line >= start && line <= end {
+                    None
+                } else {
+                    Some((shift_line(line, start, end, new_end) - new_base, segments))
+                }
+            })
+            .collect();
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -93,26 +147,61 @@ impl FileLineMappings {
         self.access(mapping.section).push(mapping);
     }
 
+    fn remove_dst_file(&mut self, path: &Path) {
+        self.none.retain(|l| l.dst_file != path);
+        self.interface.retain(|l| l.dst_file != path);
+        self.implementation.retain(|l| l.dst_file != path);
+        self.files.retain(|f| f != path);
+        self.update_length();
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut LineMapping> {
+        self.none.iter_mut().chain(&mut self.interface).chain(&mut self.implementation)
+    }
+
+    fn update_length(&mut self) {
+        self.length = self.iter_mut().map(|l| l.src_end_line).max().unwrap_or(0);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
     fn sort(&mut self) {
         self.none.sort_by_key(|l| l.src_line);
         self.interface.sort_by_key(|l| l.src_line);
         self.implementation.sort_by_key(|l| l.src_line);
     }
 
-    fn check(&self) {
-        let check_section = |section: &[LineMapping]| {
-            section.windows(2).for_each(|w| {
-                assert!(
-                    w[0].src_end_line <= w[1].src_line,
-                    "Ranges overlap: a = {:?}, b = {:?}",
-                    w[0],
-                    w[1]
-                )
-            })
-        };
-        check_section(&self.none);
-        check_section(&self.interface);
-        check_section(&self.implementation);
+    /// Repairs overlapping mappings of the (sorted) sections, by truncating the first of two
+    /// overlapping mappings, or dropping it if both start at the same line. Returns the start
+    /// lines of the mappings that were overlapped.
+    fn repair(&mut self) -> Vec<u32> {
+        let mut overlaps = Vec::new();
+        for section in [&mut self.none, &mut self.interface, &mut self.implementation] {
+            let mut i = 1;
+            while i < section.len() {
+                let next_line = section[i].src_line;
+                let prev = &mut section[i - 1];
+                if prev.src_end_line <= next_line && prev.src_line < next_line {
+                    i += 1;
+                    continue;
+                }
+                overlaps.push(next_line);
+                if prev.src_line == next_line {
+                    section.remove(i - 1);
+                } else {
+                    prev.src_end_line = next_line;
+                    let length = next_line - prev.src_line;
+                    prev.columns.retain(|offset, _| *offset <= length);
+                    i += 1;
+                }
+            }
+        }
+        if !overlaps.is_empty() {
+            self.update_length();
+        }
+        overlaps
     }
 
     fn length(&self) -> u32 {
@@ -132,9 +221,11 @@ pub enum MapDirection {
 pub struct FiascoSourceMapping {
     to_preprocess: LineMappings,
     from_preprocess: LineMappings,
+    /// Module each known preprocessed file was generated for.
+    modules: HashMap<PathBuf, String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct SourceLocation {
     pub path: PathBuf,
     pub line: u32,
@@ -146,6 +237,7 @@ impl FiascoSourceMapping {
         FiascoSourceMapping {
             to_preprocess: LineMappings::new(),
             from_preprocess: LineMappings::new(),
+            modules: HashMap::new(),
         }
     }
 
@@ -158,12 +250,14 @@ impl FiascoSourceMapping {
         }
     }
 
-    fn check(&self) {
-        for mappings in self.to_preprocess.values() {
-            mappings.check()
-        }
-        for mappings in self.from_preprocess.values() {
-            mappings.check()
+    /// Repairs overlapping mappings and adds them to the report.
+    fn repair(&mut self, report: &mut LoadReport) {
+        for line_mappings in [&mut self.to_preprocess, &mut self.from_preprocess] {
+            for (file, mappings) in line_mappings.iter_mut() {
+                for line in mappings.repair() {
+                    report.add(file, MappingProblem::Overlap { line });
+                }
+            }
         }
     }
 
@@ -226,6 +320,33 @@ impl FiascoSourceMapping {
         }
     }
 
+    /// Maps a position to all locations it is contained in, e.g. a line of an inline function
+    /// might be in the interface and the implementation of a module. The locations are ordered by
+    /// section, implementation first.
+    pub fn map_all(
+        &self,
+        direction: MapDirection,
+        path: &str,
+        line: u32,
+        character: u32,
+    ) -> Vec<(SourceLocation, PreprocessSection)> {
+        let line_mappings = self.get(direction);
+        [PreprocessSection::Implementation, PreprocessSection::Interface, PreprocessSection::None]
+            .into_iter()
+            .filter_map(|section| Self::find_mapping(line_mappings, path, line, section))
+            .map(|mapping| {
+                let location = SourceLocation {
+                    path: mapping.dst_file.clone(),
+                    line: mapping.dst_line + (line - mapping.src_line),
+                    character: mapping.map_column(line, character),
+                };
+                (location, mapping.section)
+            })
+            .collect()
+    }
+
+    /// Maps a position to the first location returned by `map_all`, or to itself if it is not
+    /// mapped at all.
     pub fn map(
         &self,
         direction: MapDirection,
@@ -233,24 +354,12 @@ impl FiascoSourceMapping {
         line: u32,
         character: u32,
     ) -> SourceLocation {
-        let line_mappings = self.get(direction);
-        // TODO: Priority to use here? Might depend on use case...
-        let mapping =
-            Self::find_mapping(line_mappings, path, line, PreprocessSection::Implementation)
-                .or_else(|| {
-                    Self::find_mapping(line_mappings, path, line, PreprocessSection::Interface)
-                })
-                .or_else(|| Self::find_mapping(line_mappings, path, line, PreprocessSection::None));
-        match mapping {
+        match self.map_all(direction, path, line, character).into_iter().next() {
             None => {
                 debug!("No mapping found for Line {} ({})", line, path);
                 SourceLocation { path: PathBuf::from(path), line, character }
             }
-            Some(mapping) => SourceLocation {
-                path: mapping.dst_file.clone(),
-                line: mapping.dst_line + (line - mapping.src_line),
-                character,
-            },
+            Some((location, _)) => location,
         }
     }
 
@@ -285,16 +394,157 @@ impl FiascoSourceMapping {
     pub fn file_length(&self, direction: MapDirection, path: &Path) -> Option<u32> {
         self.get(direction).get(path).map(FileLineMappings::length)
     }
+
+    /// Returns the files that have mappings in the given direction, i.e. the source files for
+    /// `ToPreprocess` and the preprocessed files for `FromPreprocess`.
+    pub fn files(&self, direction: MapDirection) -> impl Iterator<Item = &Path> {
+        self.get(direction).keys().map(PathBuf::as_path)
+    }
+
+    /// Returns whether the mappings of the given preprocessed file are known.
+    pub fn contains_file(&self, path: &Path) -> bool {
+        self.from_preprocess.contains_key(path)
+    }
+
+    /// Returns the module the given preprocessed file belongs to, or `None` if the file is not
+    /// an output of any module of the build directory.
+    pub fn module_of(&self, path: &Path) -> Option<&str> {
+        self.modules.get(path).map(String::as_str)
+    }
+
+    /// Re-discovers the preprocessed files of the build directory, after its modules changed.
+    /// Loads the mappings of new files and drops the mappings of files that no longer belong to
+    /// any module.
+    pub fn reload_modules(&mut self, build_dir: &Path) {
+        let modules = discover_preprocessed_files(build_dir);
+        let stale: Vec<PathBuf> =
+            self.modules.keys().filter(|path| !modules.contains_key(*path)).cloned().collect();
+        for path in &stale {
+            self.remove_file(path);
+        }
+        let added: Vec<PathBuf> =
+            modules.keys().filter(|path| !self.modules.contains_key(*path)).cloned().collect();
+        let mut sources = SourceLines::default();
+        let mut report = LoadReport::default();
+        for path in &added {
+            self.remove_file(path);
+            if let Some(mappings) = extract_line_mappings_for_file(path, &mut sources, &mut report)
+            {
+                insert_line_mappings(path, mappings, self);
+            }
+        }
+        info!(
+            "Modules changed: {} preprocessed files added, {} removed.",
+            added.len(),
+            stale.len()
+        );
+        self.modules = modules.into_iter().collect();
+        self.sort();
+        self.repair(&mut report);
+        report.log();
+    }
+
+    /// Removes all mappings from and to the given preprocessed file.
+    fn remove_file(&mut self, path: &Path) {
+        if let Some(mappings) = self.from_preprocess.remove(path) {
+            for source in &mappings.files {
+                if let Some(source_mappings) = self.to_preprocess.get_mut(source) {
+                    source_mappings.remove_dst_file(path);
+                    if source_mappings.is_empty() {
+                        self.to_preprocess.remove(source);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates all mappings from and to the given file (source or preprocessed), after its lines
+    /// `start..=end` were replaced by the lines `start..=new_end`. Mappings that contain the
+    /// edited lines grow or shrink accordingly, mappings behind them are moved.
+    pub fn shift_lines(&mut self, path: &Path, start: u32, end: u32, new_end: u32) {
+        if end == new_end {
+            return;
+        }
+
+        for line_mappings in [&mut self.to_preprocess, &mut self.from_preprocess] {
+            for (file, mappings) in line_mappings.iter_mut() {
+                let is_src = file == path;
+                for mapping in mappings.iter_mut() {
+                    if is_src {
+                        let src_line = shift_line(mapping.src_line, start, end, new_end);
+                        mapping.shift_columns(mapping.src_line, src_line, start, end, new_end);
+                        mapping.src_line = src_line;
+                        mapping.src_end_line =
+                            shift_end_line(mapping.src_end_line, start, end, new_end);
+                    }
+                    if mapping.dst_file == path {
+                        let dst_line = shift_line(mapping.dst_line, start, end, new_end);
+                        mapping.shift_columns(mapping.dst_line, dst_line, start, end, new_end);
+                        mapping.dst_line = dst_line;
+                    }
+                }
+                if is_src {
+                    mappings.update_length();
+                }
+            }
+        }
+    }
+
+    /// Re-reads the mappings of a (re-generated) preprocessed file.
+    pub fn reload_file(&mut self, path: &Path) {
+        let mut report = LoadReport::default();
+        self.remove_file(path);
+        if let Some(mappings) =
+            extract_line_mappings_for_file(path, &mut SourceLines::default(), &mut report)
+        {
+            insert_line_mappings(path, mappings, self);
+        }
+        self.sort();
+        self.repair(&mut report);
+        report.log();
+    }
+
+    /// Like `reload_file`, but takes the content of the preprocessed file, e.g. for a file
+    /// generated from unsaved sources, whose content is passed as `sources`.
+    pub fn reload_file_from_text(
+        &mut self,
+        path: &Path,
+        text: &str,
+        sources: &HashMap<PathBuf, String>,
+    ) {
+        let mut report = LoadReport::default();
+        self.remove_file(path);
+        let file_name = path.file_name().and_then(OsStr::to_str).unwrap();
+        let lines: Vec<String> = text.lines().map(str::to_owned).collect();
+        let mut problems = Vec::new();
+        let mut mappings = extract_line_mappings(
+            file_name,
+            lines.iter().cloned().map(Ok).enumerate(),
+            &mut problems,
+        );
+        report.extend(path, problems);
+        add_column_mappings(&mut mappings, &lines, &mut SourceLines::with_overlay(sources));
+        insert_line_mappings(path, mappings, self);
+        self.sort();
+        self.repair(&mut report);
+        report.log();
+    }
 }
 
 lazy_static! {
     static ref NAME_REPLACE_RE: Regex = Regex::new(r"[+-.]").unwrap();
-    static ref LINE_REF_RE: Regex = Regex::new(r#"^#line (\d+) "(.+)"$"#).unwrap();
+    static ref LINE_REF_RE: Regex = Regex::new(r#"^#line (\S+) "(.+)"$"#).unwrap();
     static ref INTERFACE_SEC_RE: Regex = Regex::new(r"^// INTERFACE").unwrap();
     static ref IMPLEMENTATION_SEC_RE: Regex = Regex::new(r"^// IMPLEMENTATION").unwrap();
 }
 
-fn extract_line_mappings<I>(name: &str, lines: I) -> Vec<LineMapping>
+/// Extracts the line mappings of a preprocessed file from its `#line` directives. Problems are
+/// added to `problems`, the affected mappings are dropped.
+fn extract_line_mappings<I>(
+    name: &str,
+    lines: I,
+    problems: &mut Vec<MappingProblem>,
+) -> Vec<LineMapping>
 where
     I: IntoIterator<Item = (usize, io::Result<String>)>,
 {
@@ -303,62 +553,158 @@ where
     let mut mappings: Vec<LineMapping> = Vec::new();
     let mut ln = 0;
     let mut ln_offset = 0;
+    // Line of the marker preprocess generated the `ln_offset` lines for.
+    let mut marker_line = 0;
+    // Whether the last mapping extends up to the current line.
+    let mut open = true;
+    let mut found_endif = false;
     for (l, r) in lines {
         ln = l;
         if let Ok(line) = r {
             if let Some(cap) = LINE_REF_RE.captures(line.as_str()) {
-                if let Some(m) = mappings.last_mut() {
-                    m.src_end_line = max(m.src_line, l as u32 - ln_offset - 1)
+                if let Some(m) = mappings.last_mut().filter(|_| open) {
+                    let end = match (l as u32).checked_sub(ln_offset + 1) {
+                        Some(end) if l >= marker_line + ln_offset as usize => end,
+                        _ => {
+                            // End the mapping in front of the marker instead.
+                            problems
+                                .push(MappingProblem::MisplacedLineDirective { line: l as u32 });
+                            (marker_line as u32).saturating_sub(1)
+                        }
+                    };
+                    m.src_end_line = max(m.src_line, end)
+                };
+                ln_offset = 0;
+                let Some(dst_line) = cap[1].parse::<u32>().ok().and_then(|n| n.checked_sub(1))
+                else {
+                    // Leave the lines up to the next #line directive unmapped.
+                    problems.push(MappingProblem::InvalidLineNumber { line: l as u32 });
+                    open = false;
+                    continue;
                 };
                 mappings.push(LineMapping {
                     section: cur_section,
                     src_line: l as u32 + 1, // Adjust for the #line comment itself
                     src_end_line: 0,        // Set later
                     dst_file: PathBuf::from(&cap[2]),
-                    dst_line: cap[1].parse::<u32>().unwrap_or(1) - 1,
+                    dst_line,
+                    columns: BTreeMap::new(),
                 });
-                ln_offset = 0;
+                open = true;
             } else if line.starts_with("// INTERFACE") {
                 cur_section = PreprocessSection::Interface;
-                if let Some(m) = mappings.last_mut() {
+                if let Some(m) = mappings.last_mut().filter(|_| open) {
                     m.src_end_line = l as u32
                 };
                 // Do not include the preprocess generated comments into the mapping.
                 ln_offset = 5;
+                marker_line = l;
             } else if line.starts_with("// IMPLEMENTATION") {
                 cur_section = PreprocessSection::Implementation;
-                if let Some(m) = mappings.last_mut() {
+                if let Some(m) = mappings.last_mut().filter(|_| open) {
                     m.src_end_line = l as u32
                 };
                 // Do not include the preprocess generated comments into the mapping.
                 ln_offset = 5;
+                marker_line = l;
             } else if line.starts_with("private: // EXTENSION") {
                 // Do not include the following three preprocess generated lines into the mapping,
                 // to avoid artificial overlaps with other mappings.
                 ln_offset = 3;
+                marker_line = l;
             } else if line.starts_with(&endif_pattern) {
                 // Reached the end of the file, do not include the #endif generated by preprocess
                 // into the mapping, to avoid artificial overlaps with other mappings.
-                ln -= 1;
+                ln = l.saturating_sub(1);
+                found_endif = true;
                 break;
             }
         }
     }
-    if let Some(m) = mappings.last_mut() {
-        m.src_end_line = ln as u32 - ln_offset
+    if let Some(m) = mappings.last_mut().filter(|_| open) {
+        m.src_end_line = max(m.src_line, (ln as u32).saturating_sub(ln_offset))
     };
+    // Headers generated by preprocess end with an include guard, otherwise the file is likely
+    // truncated, e.g. by an interrupted build.
+    if !found_endif && name.ends_with(".h") {
+        problems.push(MappingProblem::MissingEndif);
+    }
     mappings
 }
 
-fn extract_line_mappings_for_file(path: &Path, source_mapping: &mut FiascoSourceMapping) {
-    let file = File::open(path);
-    if file.is_err() {
-        return;
+/// Lines of source files, read on demand to compute column mappings.
+#[derive(Default)]
+struct SourceLines {
+    files: HashMap<PathBuf, Option<Vec<String>>>,
+}
+
+impl SourceLines {
+    fn with_overlay(overlay: &HashMap<PathBuf, String>) -> SourceLines {
+        let files = overlay
+            .iter()
+            .map(|(path, text)| (path.clone(), Some(text.lines().map(str::to_owned).collect())))
+            .collect();
+        SourceLines { files }
+    }
+
+    fn get(&mut self, path: &Path) -> Option<&[String]> {
+        self.files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                fs::read_to_string(path).ok().map(|text| text.lines().map(str::to_owned).collect())
+            })
+            .as_deref()
     }
+}
 
-    let reader = BufReader::new(file.unwrap());
+/// Compares the lines of each mapping with the corresponding source lines and adds column
+/// segments for those that were changed by preprocess.
+fn add_column_mappings(mappings: &mut [LineMapping], lines: &[String], sources: &mut SourceLines) {
+    for mapping in mappings {
+        let Some(source) = sources.get(&mapping.dst_file) else {
+            continue;
+        };
+        for offset in 0..=mapping.src_end_line.saturating_sub(mapping.src_line) {
+            let generated = lines.get((mapping.src_line + offset) as usize);
+            let original = source.get((mapping.dst_line + offset) as usize);
+            let (Some(generated), Some(original)) = (generated, original) else {
+                break;
+            };
+            if generated != original {
+                mapping.columns.insert(offset, diff_columns(generated, original));
+            }
+        }
+    }
+}
+
+fn extract_line_mappings_for_file(
+    path: &Path,
+    sources: &mut SourceLines,
+    report: &mut LoadReport,
+) -> Option<Vec<LineMapping>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            report.add(path, MappingProblem::Unreadable { error: err.to_string() });
+            return None;
+        }
+    };
+    let reader = BufReader::new(file);
+    let lines: Vec<String> = reader.lines().map_while(Result::ok).collect();
     let file_name = path.file_name().and_then(OsStr::to_str).unwrap();
-    let mappings = extract_line_mappings(file_name, reader.lines().enumerate());
+    let mut problems = Vec::new();
+    let mut mappings =
+        extract_line_mappings(file_name, lines.iter().cloned().map(Ok).enumerate(), &mut problems);
+    report.extend(path, problems);
+    add_column_mappings(&mut mappings, &lines, sources);
+    Some(mappings)
+}
+
+fn insert_line_mappings(
+    path: &Path,
+    mappings: Vec<LineMapping>,
+    source_mapping: &mut FiascoSourceMapping,
+) {
     for mapping in &mappings {
         if !source_mapping.to_preprocess.contains_key(&mapping.dst_file) {
             source_mapping.to_preprocess.insert(mapping.dst_file.clone(), FileLineMappings::new());
@@ -369,6 +715,13 @@ fn extract_line_mappings_for_file(path: &Path, source_mapping: &mut FiascoSource
             src_end_line: mapping.dst_line + (mapping.src_end_line - mapping.src_line),
             dst_file: path.to_path_buf(),
             dst_line: mapping.src_line,
+            columns: mapping
+                .columns
+                .iter()
+                .map(|(offset, segments)| {
+                    (*offset, segments.iter().map(ColumnSegment::inverse).collect())
+                })
+                .collect(),
         })
     }
     source_mapping
@@ -376,16 +729,169 @@ fn extract_line_mappings_for_file(path: &Path, source_mapping: &mut FiascoSource
         .insert(path.to_path_buf(), FileLineMappings::from_mappings(mappings));
 }
 
+/// Version of the mapping dump schema, incremented on incompatible changes.
+pub const DUMP_VERSION: u32 = 1;
+
+/// Mapping table in the stable schema of `fiasco-lsp map --dump`, see the README. Unlike the
+/// internal representation, lines are one-based.
+#[derive(Debug, Serialize)]
+pub struct MappingDump {
+    pub version: u32,
+    pub files: Vec<FileDump>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileDump {
+    pub preprocessed: PathBuf,
+    pub module: Option<String>,
+    pub mappings: Vec<MappingDumpEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MappingDumpEntry {
+    pub section: &'static str,
+    pub preprocessed_line: u32,
+    pub source: PathBuf,
+    pub source_line: u32,
+    pub lines: u32,
+}
+
+impl FiascoSourceMapping {
+    pub fn dump(&self) -> MappingDump {
+        let mut files: Vec<FileDump> = self
+            .from_preprocess
+            .iter()
+            .map(|(path, mappings)| {
+                let mut entries: Vec<&LineMapping> = mappings
+                    .none
+                    .iter()
+                    .chain(&mappings.interface)
+                    .chain(&mappings.implementation)
+                    .collect();
+                entries.sort_by_key(|mapping| mapping.src_line);
+                FileDump {
+                    preprocessed: path.clone(),
+                    module: self.module_of(path).map(str::to_owned),
+                    mappings: entries
+                        .into_iter()
+                        .map(|mapping| MappingDumpEntry {
+                            section: match mapping.section {
+                                PreprocessSection::None => "none",
+                                PreprocessSection::Interface => "interface",
+                                PreprocessSection::Implementation => "implementation",
+                            },
+                            preprocessed_line: mapping.src_line + 1,
+                            source: mapping.dst_file.clone(),
+                            source_line: mapping.dst_line + 1,
+                            lines: mapping.src_end_line.saturating_sub(mapping.src_line) + 1,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        files.sort_by(|a, b| a.preprocessed.cmp(&b.preprocessed));
+        MappingDump { version: DUMP_VERSION, files }
+    }
+}
+
+/// Problem found while loading the line mappings of a file. Line numbers are zero-based.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MappingProblem {
+    /// The file or directory could not be read.
+    Unreadable { error: String },
+    /// A `#line` directive whose line number is not a positive number. The lines up to the next
+    /// `#line` directive are not mapped.
+    InvalidLineNumber { line: u32 },
+    /// A `#line` directive within the lines preprocess generates after a section marker or an
+    /// extension. The preceding mapping ends in front of the marker.
+    MisplacedLineDirective { line: u32 },
+    /// The include guard `#endif` that preprocess generates at the end of a header is missing.
+    MissingEndif,
+    /// A mapping overlapped the mapping starting at `line`, and was truncated or dropped.
+    Overlap { line: u32 },
+}
+
+impl fmt::Display for MappingProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingProblem::Unreadable { error } => write!(f, "unreadable: {error}"),
+            MappingProblem::InvalidLineNumber { line } => {
+                write!(f, "invalid #line number in line {}", line + 1)
+            }
+            MappingProblem::MisplacedLineDirective { line } => {
+                write!(f, "#line within generated lines in line {}", line + 1)
+            }
+            MappingProblem::MissingEndif => write!(f, "missing #endif include guard"),
+            MappingProblem::Overlap { line } => {
+                write!(f, "overlapping mappings at line {}", line + 1)
+            }
+        }
+    }
+}
+
+/// Problems found while loading the line mappings, by file.
+#[derive(Debug, Default, Serialize)]
+pub struct LoadReport {
+    pub problems: BTreeMap<PathBuf, Vec<MappingProblem>>,
+}
+
+impl LoadReport {
+    fn add(&mut self, path: &Path, problem: MappingProblem) {
+        self.problems.entry(path.to_path_buf()).or_default().push(problem);
+    }
+
+    fn extend(&mut self, path: &Path, problems: Vec<MappingProblem>) {
+        if !problems.is_empty() {
+            self.problems.entry(path.to_path_buf()).or_default().extend(problems);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// One-line summary, suitable for `window/showMessage`.
+    pub fn summary(&self) -> String {
+        let count: usize = self.problems.values().map(Vec::len).sum();
+        format!(
+            "Fiasco LSP: {} problems in the line mappings of {} files, affected lines might not \
+             be mapped correctly. See the log for details.",
+            count,
+            self.problems.len()
+        )
+    }
+
+    /// Logs every problem, as well as the whole report as JSON.
+    pub fn log(&self) {
+        if self.is_empty() {
+            return;
+        }
+        for (path, problems) in &self.problems {
+            for problem in problems {
+                warn!("{}: {}", path.display(), problem);
+            }
+        }
+        info!("Mapping report: {}", serde_json::to_string(self).unwrap());
+    }
+}
+
 lazy_static! {
     static ref STAMP_RE: Regex = Regex::new(r"^auto/stamp-(.+).ready:\s*(.+)$").unwrap();
 }
 
 pub fn load_modules(build_dir: &str) -> HashMap<String, Vec<String>> {
-    let file = File::open(Path::new(build_dir).join(".Modules.deps")).unwrap();
+    let file = match File::open(Path::new(build_dir).join(".Modules.deps")) {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Unable to read module dependencies in {}: {}", build_dir, err);
+            return HashMap::new();
+        }
+    };
     let reader = BufReader::new(file);
     reader
         .lines()
-        .filter_map(Result::ok)
+        .map_while(Result::ok)
         .filter_map(|line| {
             STAMP_RE.captures(&line).map(|cap| {
                 (cap[1].to_owned(), cap[2].split_whitespace().map(str::to_owned).collect())
@@ -394,75 +900,392 @@ pub fn load_modules(build_dir: &str) -> HashMap<String, Vec<String>> {
         .collect()
 }
 
-pub fn load_source_mapping(build_dir: &Path) -> FiascoSourceMapping {
-    // let cdb_file = Path::new(json_compilation_db::DEFAULT_FILE_NAME);
-    // let entries = json_compilation_db::from_file(cdb_file).unwrap_or(vec![]);
+/// Name of the line mapping cache in the build directory.
+const CACHE_FILE_NAME: &str = ".fiasco-lsp-mappings.json";
+/// Must be incremented whenever the format of `LineMapping` changes.
+const CACHE_VERSION: u32 = 2;
+
+/// Line mappings of the preprocessed files, as persisted in the build directory. Each entry is
+/// only valid as long as the modification time and size of its preprocessed file are unchanged.
+#[derive(Default, Serialize, Deserialize)]
+struct MappingCache {
+    version: u32,
+    files: HashMap<PathBuf, CachedMappings>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMappings {
+    mtime: SystemTime,
+    size: u64,
+    mappings: Vec<LineMapping>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<MappingProblem>,
+}
+
+impl MappingCache {
+    fn load(build_dir: &Path) -> MappingCache {
+        let cache: MappingCache = match File::open(build_dir.join(CACHE_FILE_NAME)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|err| {
+                warn!("Ignoring invalid line mapping cache: {}", err);
+                MappingCache::default()
+            }),
+            Err(_) => MappingCache::default(),
+        };
+        if cache.version != CACHE_VERSION {
+            return MappingCache { version: CACHE_VERSION, files: HashMap::new() };
+        }
+        cache
+    }
+
+    fn store(&self, build_dir: &Path) -> io::Result<()> {
+        // Write to a temporary file first, so that an interrupted write does not leave behind a
+        // broken cache.
+        let path = build_dir.join(CACHE_FILE_NAME);
+        let temp_path = path.with_extension("tmp");
+        let file = File::create(&temp_path)?;
+        serde_json::to_writer(io::BufWriter::new(file), self)?;
+        fs::rename(temp_path, path)
+    }
+
+    /// Returns the cached mappings of a preprocessed file, if the file is unchanged.
+    fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<&CachedMappings> {
+        let cached = self.files.get(path)?;
+        let mtime = metadata.modified().ok()?;
+        (cached.mtime == mtime && cached.size == metadata.len()).then_some(cached)
+    }
+}
+
+/// Returns the preprocessed outputs of a module, that exist in the build directory.
+pub fn module_outputs(build_dir: &Path, module: &str) -> Vec<PathBuf> {
+    [format!("{module}.cpp"), format!("{module}.h"), format!("{module}_i.h")]
+        .into_iter()
+        .map(|name| build_dir.join("auto").join(name))
+        .filter(|path| path.exists())
+        .collect()
+}
 
-    // TODO: There are also files without specific prefixes...
+/// Reads the files of the compilation database of the build directory, that are located in the
+/// auto directory.
+fn load_compile_db_files(build_dir: &Path) -> Vec<PathBuf> {
+    let cdb_file = build_dir.join(json_compilation_db::DEFAULT_FILE_NAME);
+    let Ok(file) = File::open(&cdb_file) else {
+        return vec![];
+    };
+    json_compilation_db::read(BufReader::new(file))
+        .map_while(|entry| {
+            entry.map_err(|err| warn!("Invalid entry in {}: {}", cdb_file.display(), err)).ok()
+        })
+        .map(|entry| entry.directory.join(entry.file))
+        // Preprocessed files are located in the auto directory
+        .filter(|file| file.parent().is_some_and(|dir| dir.ends_with("auto")))
+        .filter_map(|file| file.file_name().map(|name| build_dir.join("auto").join(name)))
+        .collect()
+}
+
+/// Discovers the preprocessed files of the build directory and the module each of them belongs
+/// to. The modules are read from `.Modules.deps`, complemented by the preprocessed files that are
+/// compiled according to the compilation database. Other files in the auto directory, e.g. stale
+/// outputs of removed modules, are ignored.
+pub fn discover_preprocessed_files(build_dir: &Path) -> BTreeMap<PathBuf, String> {
+    let modules = load_modules(build_dir.to_str().unwrap());
+    let mut files = BTreeMap::new();
+    for module in modules.keys() {
+        for path in module_outputs(build_dir, module) {
+            files.insert(path, module.clone());
+        }
+    }
+
+    for file in load_compile_db_files(build_dir) {
+        if files.contains_key(&file) || !file.exists() {
+            continue;
+        }
+        let stem = file.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+        // A module might be split into several implementation files named `<module>-<part>`.
+        let module = modules
+            .keys()
+            .filter(|module| {
+                stem.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with('-'))
+            })
+            .max_by_key(|module| module.len())
+            .cloned()
+            .unwrap_or_else(|| stem.to_owned());
+        for path in module_outputs(build_dir, &module).into_iter().chain([file]) {
+            files.entry(path).or_insert_with(|| module.clone());
+        }
+    }
+    files
+}
+
+/// Loads the line mappings of all preprocessed files of the build directory. Files that cannot be
+/// loaded, or only partially, are listed in the returned report.
+pub fn load_source_mapping(build_dir: &Path) -> (FiascoSourceMapping, LoadReport) {
     let mut source_mapping = FiascoSourceMapping::new();
-    let paths = fs::read_dir(build_dir.join("auto")).unwrap();
-    for path in paths {
-        let p = path.unwrap();
-        extract_line_mappings_for_file(&p.path(), &mut source_mapping);
-    }
-    /*
-    for entry in &entries {
-        // Preprocessed file are located in the auto directory
-        if entry.file.parent().map(|f| f.ends_with("auto")).unwrap_or(false) {
-            extract_line_mappings_for_file(&entry.file, &mut source_mapping);
-            extract_line_mappings_for_file(&entry.file.with_extension("h"),
-                                           &mut source_mapping);
-            if let Some(stem) = entry.file.file_stem() {
-                let mut file_name = stem.to_owned();
-                file_name.push("_i.h");
-                extract_line_mappings_for_file(&entry.file.with_file_name(file_name),
-                                               &mut source_mapping);
+    let mut report = LoadReport::default();
+    let auto_dir = build_dir.join("auto");
+    if let Err(err) = fs::read_dir(&auto_dir) {
+        report.add(&auto_dir, MappingProblem::Unreadable { error: err.to_string() });
+    }
+
+    let mut sources = SourceLines::default();
+    let cache = MappingCache::load(build_dir);
+    let mut new_cache = MappingCache { version: CACHE_VERSION, files: HashMap::new() };
+    let mut parsed = 0;
+    let files = discover_preprocessed_files(build_dir);
+    for p in files.keys() {
+        let Ok(metadata) = fs::metadata(p) else {
+            continue;
+        };
+        let (mappings, problems) = match cache.get(p, &metadata) {
+            Some(cached) => (cached.mappings.clone(), cached.problems.clone()),
+            None => {
+                let mut file_report = LoadReport::default();
+                let mappings = extract_line_mappings_for_file(p, &mut sources, &mut file_report);
+                let problems = file_report.problems.remove(p).unwrap_or_default();
+                let Some(mappings) = mappings else {
+                    report.extend(p, problems);
+                    continue;
+                };
+                parsed += 1;
+                (mappings, problems)
             }
+        };
+        if let Ok(mtime) = metadata.modified() {
+            new_cache.files.insert(
+                p.clone(),
+                CachedMappings {
+                    mtime,
+                    size: metadata.len(),
+                    mappings: mappings.clone(),
+                    problems: problems.clone(),
+                },
+            );
         }
+        report.extend(p, problems);
+        insert_line_mappings(p, mappings, &mut source_mapping);
     }
-    */
+    info!("Parsed {} of {} preprocessed files.", parsed, new_cache.files.len());
+    if parsed > 0 || new_cache.files.len() != cache.files.len() {
+        if let Err(err) = new_cache.store(build_dir) {
+            warn!("Unable to store line mapping cache: {}", err);
+        }
+    }
+    source_mapping.modules = files.into_iter().collect();
     source_mapping.sort();
-    source_mapping.check();
-    source_mapping
+    source_mapping.repair(&mut report);
+    report.log();
+    (source_mapping, report)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::BufWriter;
-
     use super::*;
 
     #[test]
-    fn load_mods() {
-        let modules = load_modules("/home/george/kk/build/build-fiasco-arm64/auto/");
-        env_logger::init();
-        println!("{:?}", modules);
+    fn discover() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path();
+        let auto = build_dir.join("auto");
+        fs::create_dir(&auto).unwrap();
+        fs::write(
+            build_dir.join(".Modules.deps"),
+            "auto/stamp-foo.ready: src/foo.cpp src/foo-ia32.cpp\n",
+        )
+        .unwrap();
+        let cdb = serde_json::json!([
+            { "directory": build_dir, "file": "auto/foo-ia32.cpp", "arguments": ["g++"] },
+            { "directory": build_dir, "file": "auto/bar.cpp", "arguments": ["g++"] },
+            { "directory": build_dir, "file": "main.cpp", "arguments": ["g++"] },
+        ]);
+        fs::write(build_dir.join("compile_commands.json"), cdb.to_string()).unwrap();
+        for name in ["foo.cpp", "foo.h", "foo_i.h", "foo-ia32.cpp", "bar.cpp", "bar.h", "stale.cpp"]
+        {
+            fs::write(auto.join(name), "").unwrap();
+        }
+
+        let files = discover_preprocessed_files(build_dir);
+        let module = |name: &str| files.get(&auto.join(name)).map(String::as_str);
+        assert_eq!(module("foo.cpp"), Some("foo"));
+        assert_eq!(module("foo.h"), Some("foo"));
+        assert_eq!(module("foo_i.h"), Some("foo"));
+        assert_eq!(module("foo-ia32.cpp"), Some("foo"));
+        assert_eq!(module("bar.cpp"), Some("bar"));
+        assert_eq!(module("bar.h"), Some("bar"));
+        assert_eq!(module("stale.cpp"), None);
+        assert_eq!(files.len(), 6);
     }
 
     #[test]
-    fn load() {
-        let source_mapping =
-            load_source_mapping(Path::new("/home/george/kk/build/build-fiasco-arm64/auto/"));
-        serde_json::to_writer_pretty(
-            BufWriter::new(File::create("source_to_preprocess.json").unwrap()),
-            &source_mapping.to_preprocess,
+    fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.cpp");
+        fs::write(&path, "#line 2 \"/src/foo.cpp\"\nint a;\nint b;\n").unwrap();
+
+        let mut source_mapping = FiascoSourceMapping::new();
+        source_mapping.reload_file(&path);
+        let mapped = source_mapping.map(MapDirection::ToPreprocess, "/src/foo.cpp", 2, 4);
+        assert_eq!((mapped.path.as_path(), mapped.line, mapped.character), (path.as_path(), 2, 4));
+
+        // Preprocess inserted a line in front of the mapped block.
+        fs::write(&path, "// foo\n#line 2 \"/src/foo.cpp\"\nint a;\nint b;\n").unwrap();
+        source_mapping.reload_file(&path);
+        let mapped = source_mapping.map(MapDirection::ToPreprocess, "/src/foo.cpp", 2, 4);
+        assert_eq!((mapped.path.as_path(), mapped.line), (path.as_path(), 3));
+        let mapped = source_mapping.map(MapDirection::FromPreprocess, path.to_str().unwrap(), 3, 4);
+        assert_eq!((mapped.path.as_path(), mapped.line), (Path::new("/src/foo.cpp"), 2));
+        assert_eq!(source_mapping.map_files(MapDirection::ToPreprocess, "/src/foo.cpp").len(), 1);
+    }
+
+    #[test]
+    fn shift_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.cpp");
+        let source = "/src/foo.cpp";
+        fs::write(&path, "#line 1 \"/src/foo.cpp\"\na\nb\n#line 11 \"/src/foo.cpp\"\nc\nd\n")
+            .unwrap();
+        let mut source_mapping = FiascoSourceMapping::new();
+        source_mapping.reload_file(&path);
+        let to = |m: &FiascoSourceMapping, line| m.map(MapDirection::ToPreprocess, source, line, 0);
+        assert_eq!(to(&source_mapping, 1).line, 2);
+        assert_eq!(to(&source_mapping, 10).line, 4);
+
+        // Insert two lines after source line 0, which is forwarded to line 1 of the preprocessed
+        // file.
+        source_mapping.shift_lines(Path::new(source), 0, 0, 2);
+        source_mapping.shift_lines(&path, 1, 1, 3);
+        assert_eq!(to(&source_mapping, 3).line, 4);
+        assert_eq!(to(&source_mapping, 12).line, 6);
+        let from = source_mapping.map(MapDirection::FromPreprocess, path.to_str().unwrap(), 6, 0);
+        assert_eq!(from.line, 12);
+
+        // Remove them again.
+        source_mapping.shift_lines(Path::new(source), 0, 2, 0);
+        source_mapping.shift_lines(&path, 1, 3, 1);
+        assert_eq!(to(&source_mapping, 1).line, 2);
+        assert_eq!(to(&source_mapping, 10).line, 4);
+
+        // Split the last line of the first block, the new line still belongs to the block.
+        source_mapping.shift_lines(Path::new(source), 1, 1, 2);
+        source_mapping.shift_lines(&path, 2, 2, 3);
+        assert_eq!(to(&source_mapping, 2).path, path);
+        assert_eq!(to(&source_mapping, 2).line, 3);
+        assert_eq!(to(&source_mapping, 11).line, 5);
+    }
+
+    #[test]
+    fn map_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("foo_i.h");
+        let implementation = dir.path().join("foo.cpp");
+        fs::write(&header, "// INTERFACE\n#line 3 \"/src/foo.cpp\"\ninline int a();\n").unwrap();
+        fs::write(&implementation, "// IMPLEMENTATION\n#line 3 \"/src/foo.cpp\"\nint b;\n")
+            .unwrap();
+        let mut source_mapping = FiascoSourceMapping::new();
+        source_mapping.reload_file(&header);
+        source_mapping.reload_file(&implementation);
+
+        let locations = source_mapping.map_all(MapDirection::ToPreprocess, "/src/foo.cpp", 2, 0);
+        let locations: Vec<_> = locations
+            .iter()
+            .map(|(location, section)| (location.path.as_path(), location.line, *section))
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                (implementation.as_path(), 2, PreprocessSection::Implementation),
+                (header.as_path(), 2, PreprocessSection::Interface)
+            ]
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("auto")).unwrap();
+        fs::write(dir.path().join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        let path = dir.path().join("auto/foo.cpp");
+        fs::write(&path, "#line 2 \"/src/foo.cpp\"\nint a;\n").unwrap();
+
+        let source_mapping = load_source_mapping(dir.path()).0;
+        assert_eq!(source_mapping.map(MapDirection::ToPreprocess, "/src/foo.cpp", 1, 0).line, 1);
+        let cache = MappingCache::load(dir.path());
+        assert!(cache.get(&path, &fs::metadata(&path).unwrap()).is_some());
+
+        // A modified file is parsed again.
+        fs::write(&path, "\n#line 2 \"/src/foo.cpp\"\nint a;\n").unwrap();
+        assert!(cache.get(&path, &fs::metadata(&path).unwrap()).is_none());
+        let source_mapping = load_source_mapping(dir.path()).0;
+        assert_eq!(source_mapping.map(MapDirection::ToPreprocess, "/src/foo.cpp", 1, 0).line, 2);
+    }
+
+    #[test]
+    fn problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let auto = dir.path().join("auto");
+        fs::create_dir(&auto).unwrap();
+        fs::write(dir.path().join(".Modules.deps"), "auto/stamp-foo.ready: foo.cpp\n").unwrap();
+        // The interface of foo.h maps the same source lines twice, and lacks the include guard.
+        fs::write(
+            auto.join("foo.h"),
+            "#line 2 \"/src/foo.cpp\"\nint a;\n#line 2 \"/src/foo.cpp\"\nint a;\n",
+        )
+        .unwrap();
+        fs::write(
+            auto.join("foo.cpp"),
+            "#line x \"/src/foo.cpp\"\nint b;\n#line 10 \"/src/foo.cpp\"\nint c;\n",
         )
         .unwrap();
-        serde_json::to_writer_pretty(
-            BufWriter::new(File::create("preprocess_to_source.json").unwrap()),
-            &source_mapping.from_preprocess,
+        // The #line directive replaces the comment lines preprocess generates after the marker.
+        fs::write(
+            auto.join("foo_i.h"),
+            "#line 20 \"/src/foo.cpp\"\nint d;\n// IMPLEMENTATION\n#line 30 \"/src/foo.cpp\"\nint e;\n\
+             #endif // foo_i_h\n",
         )
         .unwrap();
 
-        env_logger::init();
-        let mut mapped = source_mapping.map(
-            MapDirection::ToPreprocess,
-            "/home/george/kk/dev/l4re/fiasco/src/kern/arm/cpu-arm.cpp",
-            367,
-            10,
+        let (source_mapping, report) = load_source_mapping(dir.path());
+        assert_eq!(report.problems[&auto.join("foo.h")], vec![MappingProblem::MissingEndif]);
+        assert_eq!(
+            report.problems[&auto.join("foo.cpp")],
+            vec![MappingProblem::InvalidLineNumber { line: 0 }]
         );
-        mapped.line += 1;
-        mapped.character += 1;
-        println!("{:?}", mapped);
+        assert_eq!(
+            report.problems[Path::new("/src/foo.cpp")],
+            vec![MappingProblem::Overlap { line: 1 }]
+        );
+        assert_eq!(
+            report.problems[&auto.join("foo_i.h")],
+            vec![MappingProblem::MisplacedLineDirective { line: 3 }]
+        );
+
+        // The remaining mappings still work.
+        let mapped = source_mapping.map(
+            MapDirection::FromPreprocess,
+            auto.join("foo.cpp").to_str().unwrap(),
+            3,
+            0,
+        );
+        assert_eq!((mapped.path.as_path(), mapped.line), (Path::new("/src/foo.cpp"), 9));
+        let mapped = source_mapping.map(
+            MapDirection::FromPreprocess,
+            auto.join("foo.cpp").to_str().unwrap(),
+            1,
+            0,
+        );
+        assert_eq!(mapped.path, auto.join("foo.cpp"));
+        let mapped = source_mapping.map(MapDirection::ToPreprocess, "/src/foo.cpp", 1, 0);
+        assert_eq!((mapped.path.as_path(), mapped.line), (auto.join("foo.h").as_path(), 3));
+        let header = auto.join("foo_i.h");
+        let mapped =
+            source_mapping.map(MapDirection::FromPreprocess, header.to_str().unwrap(), 1, 0);
+        assert_eq!((mapped.path.as_path(), mapped.line), (Path::new("/src/foo.cpp"), 19));
+        let mapped =
+            source_mapping.map(MapDirection::FromPreprocess, header.to_str().unwrap(), 4, 0);
+        assert_eq!((mapped.path.as_path(), mapped.line), (Path::new("/src/foo.cpp"), 29));
+
+        // An include guard without any content.
+        let mut problems = Vec::new();
+        let lines = [(0, Ok("#endif // foo_h".to_owned()))];
+        assert!(extract_line_mappings("foo.h", lines, &mut problems).is_empty());
+        assert!(problems.is_empty());
     }
 }
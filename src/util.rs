@@ -2,10 +2,11 @@ use std::collections::HashSet;
 use std::path::Path;
 
 use lsp_server::{Notification, Request, RequestId, Response};
-use lsp_types::{Location, Position, Range, Url};
+use lsp_types::{Location, Position, Range, TextDocumentContentChangeEvent, Url};
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::source_mapping::{FiascoSourceMapping, MapDirection};
+use crate::configuration::SourceMappings;
+use crate::source_mapping::MapDirection;
 
 #[derive(Debug)]
 pub enum CastError<T> {
@@ -66,7 +67,44 @@ where
     Notification::new(N::METHOD.to_owned(), params)
 }
 
-impl FiascoSourceMapping {
+/// Removes duplicates from a vector, keeping the first occurrence.
+pub fn dedup<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+    let mut result = Vec::with_capacity(items.len());
+    for item in items {
+        if !result.contains(&item) {
+            result.push(item);
+        }
+    }
+    result
+}
+
+/// Returns the byte offset of an LSP position (UTF-16 based) in the given text.
+fn position_offset(text: &str, position: Position) -> usize {
+    let line_start: usize =
+        text.split_inclusive('\n').take(position.line as usize).map(str::len).sum();
+    let mut character = 0;
+    for (offset, c) in text[line_start..].char_indices() {
+        if character >= position.character || c == '\n' {
+            return line_start + offset;
+        }
+        character += c.len_utf16() as u32;
+    }
+    text.len()
+}
+
+/// Applies a content change, as received via `textDocument/didChange`, to a document's text.
+pub fn apply_content_change(text: &mut String, change: &TextDocumentContentChangeEvent) {
+    match change.range {
+        Some(range) => {
+            let start = position_offset(text, range.start);
+            let end = position_offset(text, range.end).max(start);
+            text.replace_range(start..end, &change.text);
+        }
+        None => text.clone_from(&change.text),
+    }
+}
+
+impl SourceMappings {
     pub fn map_position(
         &self,
         direction: MapDirection,
@@ -115,6 +153,30 @@ impl FiascoSourceMapping {
         Ok(())
     }
 
+    /// Maps a range to all locations it is contained in, like `map_all`. Ranges whose start and
+    /// end map to different files are skipped.
+    pub fn map_range_all(
+        &self,
+        direction: MapDirection,
+        path: &str,
+        range: &Range,
+    ) -> Vec<(String, Range)> {
+        let ends = self.map_all(direction, path, range.end.line, range.end.character);
+        self.map_all(direction, path, range.start.line, range.start.character)
+            .into_iter()
+            .filter_map(|(start, section)| {
+                let (end, _) = ends
+                    .iter()
+                    .find(|(end, end_section)| end.path == start.path && *end_section == section)?;
+                let range = Range::new(
+                    Position::new(start.line, start.character),
+                    Position::new(end.line, end.character),
+                );
+                Some((start.path.to_str().unwrap().to_owned(), range))
+            })
+            .collect()
+    }
+
     pub fn map_range_uri(
         &self,
         direction: MapDirection,
@@ -151,3 +213,45 @@ impl FiascoSourceMapping {
         self.map_file_range(direction, uri.path(), range)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(range: Option<Range>, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent { range, range_length: None, text: text.to_owned() }
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        // 'é' is one UTF-16 code unit but two bytes, '😀' a surrogate pair of four bytes.
+        let text = "aé😀b\nx\n";
+        assert_eq!(position_offset(text, Position::new(0, 2)), 3);
+        assert_eq!(position_offset(text, Position::new(0, 4)), 7);
+        // A position within the surrogate pair ends up behind it.
+        assert_eq!(position_offset(text, Position::new(0, 3)), 7);
+        // Past the end of the line and of the file.
+        assert_eq!(position_offset(text, Position::new(0, 100)), 8);
+        assert_eq!(position_offset(text, Position::new(1, 1)), 10);
+        assert_eq!(position_offset(text, Position::new(5, 0)), text.len());
+    }
+
+    #[test]
+    fn content_changes() {
+        let mut text = "aé😀b\nx\n".to_owned();
+        apply_content_change(
+            &mut text,
+            &change(Some(Range::new(Position::new(0, 2), Position::new(0, 4))), "c"),
+        );
+        assert_eq!(text, "aécb\nx\n");
+
+        apply_content_change(
+            &mut text,
+            &change(Some(Range::new(Position::new(0, 3), Position::new(9, 0))), "\ny"),
+        );
+        assert_eq!(text, "aéc\ny");
+
+        apply_content_change(&mut text, &change(None, "full\n"));
+        assert_eq!(text, "full\n");
+    }
+}
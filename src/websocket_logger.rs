@@ -1,22 +1,32 @@
-use std::net::TcpListener;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
 use std::thread::spawn;
+use std::time::Duration;
 
 use color_eyre::eyre::{Context, Result};
-use crossbeam_channel::{bounded, select, Receiver, Sender, TrySendError};
+use crossbeam_channel::{bounded, select, Receiver, RecvTimeoutError, Sender, TrySendError};
 use lsp_server::Message;
 use serde_json::json;
-use tungstenite::accept;
 use tungstenite::Message::Text;
+use tungstenite::{accept, WebSocket};
 
 use crate::global_state::Direction;
 
+/// Port the logger listens on, unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 9981;
+
+/// Interval in which the logger checks whether it was dropped while waiting for a connection.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
 pub struct Logger {
     sender: Sender<String>,
     receiver: Receiver<String>,
+    /// Disconnected when the logger is dropped, which stops its thread and frees the port.
+    _stop: Sender<()>,
 }
 
 impl Direction {
-    fn to_lsp_log(&self) -> u32 {
+    fn to_lsp_log(self) -> u32 {
         match self {
             Direction::ToServer => 1,
             Direction::FromServer => 2,
@@ -25,43 +35,96 @@ impl Direction {
 }
 
 impl Logger {
-    pub fn spawn() -> Logger {
+    pub fn spawn(port: u16) -> Logger {
         let (sender, receiver) = bounded(1024);
-        info!("Spawn logger!");
+        let (stop_sender, stop) = bounded(0);
+        info!("Spawn logger on port {port}!");
         spawn({
             let receiver = receiver.clone();
-            move || Self::log_socket_handler(receiver)
+            move || Self::log_socket_handler(port, receiver, stop)
         });
-        Logger { sender, receiver }
+        Logger { sender, receiver, _stop: stop_sender }
+    }
+
+    fn log_socket_handler(port: u16, receiver: Receiver<String>, stop: Receiver<()>) {
+        let server = match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(server) => server,
+            Err(err) => {
+                error!("Unable to listen for logger websocket on port {port}: {err}");
+                return;
+            }
+        };
+        // Poll for connections, a blocking accept would keep the port bound after the logger
+        // was replaced.
+        if let Err(err) = server.set_nonblocking(true) {
+            error!("Unable to listen for logger websocket on port {port}: {err}");
+            return;
+        }
+        let do_accept = |reconnect| {
+            if reconnect {
+                warn!("Lost connection to logger websocket, accept new connection.");
+            }
+            let client = Self::accept_client(&server, &stop)?;
+            info!("Connected to logger websocket.");
+            Some(client)
+        };
+
+        let Some(mut websocket) = do_accept(false) else {
+            return;
+        };
+        loop {
+            select! {
+                recv(receiver) -> r => {
+                    // The logger was replaced, e.g. to listen on another port.
+                    let Ok(msg) = r else {
+                        return;
+                    };
+                    if !websocket.can_write() {
+                        let Some(client) = do_accept(true) else {
+                            return;
+                        };
+                        websocket = client;
+                    }
+                    trace!("Sending message to logger: {}", msg);
+                    if let Err(err) = websocket.send(Text(msg.into())) {
+                        warn!("Error while sending message to logger: {err}");
+                        let Some(client) = do_accept(true) else {
+                            return;
+                        };
+                        websocket = client;
+                    }
+                }
+            }
+        }
     }
 
-    fn log_socket_handler(receiver: Receiver<String>) {
-        let server = TcpListener::bind("127.0.0.1:9981").unwrap();
+    /// Waits for a websocket client to connect. Returns `None` if the logger was dropped in the
+    /// meantime, or the listener failed.
+    fn accept_client(server: &TcpListener, stop: &Receiver<()>) -> Option<WebSocket<TcpStream>> {
         loop {
-            let do_accept = |reconnect| {
-                if reconnect {
-                    warn!("Lost connection to logger websocket, accept new connection.");
+            match server.accept() {
+                Ok((stream, _)) => {
+                    // The connection might inherit the non-blocking mode of the listener.
+                    if let Err(err) = stream.set_nonblocking(false) {
+                        warn!("Failed to accept logger websocket: {err}");
+                        continue;
+                    }
+                    match accept(stream) {
+                        Ok(client) => return Some(client),
+                        Err(err) => warn!("Failed to accept logger websocket: {err}"),
+                    }
                 }
-                let client = accept(server.accept().unwrap().0).unwrap();
-                info!("Connected to logger websocket.");
-                client
-            };
-
-            let mut websocket = do_accept(false);
-            loop {
-                select! {
-                    recv(receiver) -> r => {
-                        if !websocket.can_write() {
-                            websocket = do_accept(true);
-                        }
-                        let msg = r.unwrap();
-                        trace!("Sending message to logger: {}", msg);
-                        if let Err(err) = websocket.send(Text(msg.into())) {
-                            warn!("Error while sending message to logger: {err}");
-                            websocket = do_accept(true);
-                        }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if let Err(RecvTimeoutError::Disconnected) =
+                        stop.recv_timeout(ACCEPT_POLL_INTERVAL)
+                    {
+                        return None;
                     }
                 }
+                Err(err) => {
+                    error!("Unable to accept logger websocket connection: {err}");
+                    return None;
+                }
             }
         }
     }
@@ -93,7 +156,26 @@ impl Logger {
             }
         }
         .to_string();
+        self.push(log_msg)
+    }
 
+    /// Logs a message of another JSON based protocol, e.g. DAP, with the given method name.
+    pub fn send_json(
+        &mut self,
+        direction: Direction,
+        method: &str,
+        msg: &serde_json::Value,
+    ) -> Result<()> {
+        let log_msg = json!({
+            "method": method,
+            "params": msg,
+            "direction": direction.to_lsp_log(),
+        })
+        .to_string();
+        self.push(log_msg)
+    }
+
+    fn push(&mut self, log_msg: String) -> Result<()> {
         match self.sender.try_send(log_msg) {
             // Channel is full, try to remove the oldest entry.
             Err(TrySendError::Full(log_msg)) => {
@@ -109,3 +191,21 @@ impl Logger {
         .wrap_err("Failed to log message to websocket logger.")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_port() {
+        let port = TcpListener::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap().port();
+        let logger = Logger::spawn(port);
+        std::thread::sleep(ACCEPT_POLL_INTERVAL);
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_err());
+
+        // Nobody connected, the logger still stops listening.
+        drop(logger);
+        std::thread::sleep(ACCEPT_POLL_INTERVAL * 3);
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_ok());
+    }
+}